@@ -0,0 +1,308 @@
+use std::collections::HashMap;
+use std::fs::{metadata, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use flate2::read::GzDecoder;
+use tar;
+use zip;
+
+use sniff::{self, ContentKind, SNIFF_LEN};
+use source::read_limited;
+
+/// Separates the archive path from the entry name in sources, as in
+/// `photos.zip!/2017/beach.jpg`
+pub const SEPARATOR: &str = "!/";
+
+#[derive(Clone, Copy, Debug)]
+pub enum ArchiveKind {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+impl ArchiveKind {
+    pub fn from_filename(filename: &str) -> Option<Self> {
+        let lower = filename.to_lowercase();
+        if lower.ends_with(".zip") {
+            Some(ArchiveKind::Zip)
+        } else if lower.ends_with(".tar") {
+            Some(ArchiveKind::Tar)
+        } else if lower.ends_with(".tar.gz") || lower.ends_with(".tgz") {
+            Some(ArchiveKind::TarGz)
+        } else {
+            None
+        }
+    }
+}
+
+/// Entries that are loaded, others are skipped, whatever their names
+fn is_picture<R: Read>(entry: R) -> io::Result<bool> {
+    let mut prefix = vec![];
+    entry.take(SNIFF_LEN as u64).read_to_end(&mut prefix)?;
+    Ok(matches!(sniff::sniff(&prefix), Some(ContentKind::Jpeg) | Some(ContentKind::Image)))
+}
+
+/// Splits `archive!/entry` sources
+pub fn split_source(source: &str) -> Option<(&str, &str)> {
+    source.find(SEPARATOR).and_then(|i| {
+        let (archive, entry) = (&source[..i], &source[i + SEPARATOR.len()..]);
+        ArchiveKind::from_filename(archive)
+            .map(|_| (archive, entry))
+    })
+}
+
+fn zip_error(e: zip::result::ZipError) -> io::Error {
+    io::Error::other(e)
+}
+
+/// Names of the pictures in a ZIP file, which can be read in any
+/// order
+pub fn list_zip(path: &str) -> io::Result<Vec<String>> {
+    let file = File::open(path)?;
+    let mut archive = zip::ZipArchive::new(file).map_err(zip_error)?;
+    let mut names = vec![];
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i).map_err(zip_error)?;
+        let name = entry.name().to_owned();
+        if is_picture(&mut entry)? {
+            names.push(name);
+        }
+    }
+    Ok(names)
+}
+
+/// Where the pictures of a tar file are, so that they can be read in
+/// any order
+struct TarIndex {
+    /// Of the archive when it was indexed
+    modified: SystemTime,
+    /// Data offset and size by name, the offsets are only of use in
+    /// uncompressed archives
+    entries: HashMap<String, (u64, u64)>,
+}
+
+lazy_static! {
+    /// Built once per archive and version of it
+    static ref TAR_INDEXES: Mutex<HashMap<String, Arc<TarIndex>>> = Mutex::new(HashMap::new());
+}
+
+/// Names of the pictures in a tar file, which can be read in any order
+/// afterwards
+pub fn list_tar(path: &str, kind: ArchiveKind) -> io::Result<Vec<String>> {
+    let index = tar_index(path, kind)?;
+    Ok(index.entries.keys().cloned().collect())
+}
+
+fn tar_index(path: &str, kind: ArchiveKind) -> io::Result<Arc<TarIndex>> {
+    let modified = metadata(path)?.modified()?;
+    if let Some(index) = TAR_INDEXES.lock().unwrap().get(path) {
+        if index.modified == modified {
+            return Ok(index.clone())
+        }
+    }
+
+    let file = File::open(path)?;
+    let entries = match kind {
+        ArchiveKind::TarGz => index_entries(tar::Archive::new(GzDecoder::new(file)))?,
+        _ => index_entries(tar::Archive::new(file))?,
+    };
+    let index = Arc::new(TarIndex {
+        modified,
+        entries,
+    });
+    TAR_INDEXES.lock().unwrap().insert(path.to_owned(), index.clone());
+    Ok(index)
+}
+
+fn index_entries<R: Read>(mut archive: tar::Archive<R>) -> io::Result<HashMap<String, (u64, u64)>> {
+    let mut entries = HashMap::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue
+        }
+        let name = match entry.path()?.to_str() {
+            Some(name) => name.to_owned(),
+            None => continue,
+        };
+        let (offset, size) = (entry.raw_file_position(), entry.size());
+        if is_picture(&mut entry)? {
+            entries.insert(name, (offset, size));
+        }
+    }
+    Ok(entries)
+}
+
+/// Reads one entry, for sources named with `archive!/entry`
+///
+/// Fails beyond max_bytes, the sizes in the headers may lie.
+/// Compressed tar files are read from the start up to the entry.
+pub fn read_entry(path: &str, name: &str, max_bytes: u64) -> io::Result<Vec<u8>> {
+    let not_found = || io::Error::new(io::ErrorKind::NotFound, format!("{} not in {}", name, path));
+    match ArchiveKind::from_filename(path) {
+        Some(ArchiveKind::Zip) => {
+            let file = File::open(path)?;
+            let mut archive = zip::ZipArchive::new(file).map_err(zip_error)?;
+            let entry = archive.by_name(name).map_err(|_| not_found())?;
+            read_limited(entry, max_bytes)
+        },
+        Some(kind) => {
+            let index = tar_index(path, kind)?;
+            let (offset, size) = *index.entries.get(name).ok_or_else(not_found)?;
+            if size > max_bytes {
+                return Err(io::Error::other(format!("Larger than {} bytes", max_bytes)))
+            }
+            let mut file = File::open(path)?;
+            if let ArchiveKind::TarGz = kind {
+                let mut archive = tar::Archive::new(GzDecoder::new(file));
+                for entry in archive.entries()? {
+                    let entry = entry?;
+                    if entry.path()?.to_str() == Some(name) {
+                        return read_limited(entry, max_bytes)
+                    }
+                }
+                return Err(not_found())
+            }
+            file.seek(SeekFrom::Start(offset))?;
+            read_limited(file.take(size), max_bytes)
+        },
+        None =>
+            Err(not_found()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::FileTimes;
+    use std::io::Write;
+    use std::path::Path;
+    use std::time::Duration;
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use tempfile::tempdir;
+
+    const ENTRIES: &[(&str, &[u8])] = &[
+        ("b.jpg", b"\xFF\xD8\xFFsecond"),
+        ("A.JPG", b"\xFF\xD8\xFFfirst"),
+        ("notes.txt", b"not a picture"),
+        ("fake.jpg", b"not a picture either"),
+        ("2017/c.jpeg", b"\xFF\xD8\xFFthird"),
+        ("scan", b"\x89PNG\r\n\x1A\nfourth"),
+    ];
+
+    fn sorted(mut names: Vec<String>) -> Vec<String> {
+        names.sort();
+        names
+    }
+
+    fn pictures() -> Vec<String> {
+        vec!["2017/c.jpeg".to_owned(), "A.JPG".to_owned(), "b.jpg".to_owned(), "scan".to_owned()]
+    }
+
+    fn write_zip(path: &Path) {
+        let mut zip = zip::ZipWriter::new(File::create(path).unwrap());
+        for &(name, data) in ENTRIES {
+            zip.start_file(name, zip::write::FileOptions::default()).unwrap();
+            zip.write_all(data).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    fn write_tar<W: Write>(writer: W, entries: &[(&str, &[u8])]) {
+        let mut tar = tar::Builder::new(writer);
+        for &(name, data) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            tar.append_data(&mut header, name, data).unwrap();
+        }
+        tar.into_inner().unwrap();
+    }
+
+    #[test]
+    fn kinds_and_sources() {
+        assert!(matches!(ArchiveKind::from_filename("Photos.ZIP"), Some(ArchiveKind::Zip)));
+        assert!(matches!(ArchiveKind::from_filename("a.tar"), Some(ArchiveKind::Tar)));
+        assert!(matches!(ArchiveKind::from_filename("a.tgz"), Some(ArchiveKind::TarGz)));
+        assert!(matches!(ArchiveKind::from_filename("a.tar.gz"), Some(ArchiveKind::TarGz)));
+        assert!(ArchiveKind::from_filename("a.gz").is_none());
+        assert_eq!(split_source("/p/photos.zip!/2017/beach.jpg"), Some(("/p/photos.zip", "2017/beach.jpg")));
+        assert_eq!(split_source("/p/photos!/beach.jpg"), None);
+        assert_eq!(split_source("/p/beach.jpg"), None);
+    }
+
+    #[test]
+    fn zip_entries() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("photos.zip");
+        write_zip(&path);
+        let path = path.to_str().unwrap();
+
+        assert_eq!(sorted(list_zip(path).unwrap()), pictures());
+        assert_eq!(read_entry(path, "2017/c.jpeg", 100).unwrap(), b"\xFF\xD8\xFFthird");
+        assert_eq!(read_entry(path, "missing.jpg", 100).unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert!(read_entry(path, "b.jpg", 3).is_err());
+    }
+
+    #[test]
+    fn tar_entries() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("photos.tar");
+        write_tar(File::create(&path).unwrap(), ENTRIES);
+        let path = path.to_str().unwrap();
+
+        assert_eq!(sorted(list_tar(path, ArchiveKind::Tar).unwrap()), pictures());
+        assert_eq!(read_entry(path, "A.JPG", 100).unwrap(), b"\xFF\xD8\xFFfirst");
+        assert_eq!(read_entry(path, "b.jpg", 100).unwrap(), b"\xFF\xD8\xFFsecond");
+        assert_eq!(read_entry(path, "notes.txt", 100).unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert!(read_entry(path, "b.jpg", 3).is_err());
+    }
+
+    #[test]
+    fn tar_gz_entries() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("photos.tar.gz");
+        write_tar(GzEncoder::new(File::create(&path).unwrap(), Compression::default()), ENTRIES);
+        let path = path.to_str().unwrap();
+
+        assert_eq!(sorted(list_tar(path, ArchiveKind::TarGz).unwrap()), pictures());
+        assert_eq!(read_entry(path, "2017/c.jpeg", 100).unwrap(), b"\xFF\xD8\xFFthird");
+        assert_eq!(read_entry(path, "scan", 100).unwrap(), b"\x89PNG\r\n\x1A\nfourth");
+        assert_eq!(read_entry(path, "fake.jpg", 100).unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert!(read_entry(path, "b.jpg", 3).is_err());
+    }
+
+    /// A small archive that inflates to more than any entry may have
+    #[test]
+    fn tar_gz_bomb_stays_bounded() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("bomb.tgz");
+        let mut data = b"\xFF\xD8\xFF".to_vec();
+        data.resize(64 << 20, 0);
+        write_tar(GzEncoder::new(File::create(&path).unwrap(), Compression::best()), &[("big.jpg", &data)]);
+        assert!(metadata(&path).unwrap().len() < 1 << 20);
+        let path = path.to_str().unwrap();
+
+        assert_eq!(list_tar(path, ArchiveKind::TarGz).unwrap(), vec!["big.jpg".to_owned()]);
+        assert!(read_entry(path, "big.jpg", 1 << 20).is_err());
+    }
+
+    #[test]
+    fn tar_reindexed_when_changed() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("changing.tar");
+        write_tar(File::create(&path).unwrap(), &[("old.jpg", b"\xFF\xD8\xFFold")]);
+        let path_str = path.to_str().unwrap();
+        assert_eq!(list_tar(path_str, ArchiveKind::Tar).unwrap(), vec!["old.jpg".to_owned()]);
+
+        let modified = metadata(&path).unwrap().modified().unwrap();
+        write_tar(File::create(&path).unwrap(), &[("new.jpg", b"\xFF\xD8\xFFnew")]);
+        let file = File::options().write(true).open(&path).unwrap();
+        file.set_times(FileTimes::new().set_modified(modified + Duration::from_secs(1))).unwrap();
+        assert_eq!(list_tar(path_str, ArchiveKind::Tar).unwrap(), vec!["new.jpg".to_owned()]);
+        assert_eq!(read_entry(path_str, "new.jpg", 100).unwrap(), b"\xFF\xD8\xFFnew");
+    }
+}
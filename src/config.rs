@@ -0,0 +1,573 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use time;
+
+use text::TextStyle;
+use widgets::{Widget, WidgetContent};
+use window::WindowOptions;
+use render::Timing;
+use util::cache_dir;
+use http::{Auth, RequestOptions, is_credential};
+
+/// Protection against huge downloads and decompression bombs
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    /// Maximum size of a response body
+    pub max_bytes: u64,
+    /// Maximum width × height of a picture before it is decoded
+    pub max_pixels: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_bytes: 64 << 20,
+            max_pixels: 100_000_000,
+        }
+    }
+}
+
+/// Texture minification when zoomed out
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Filter {
+    Nearest,
+    Bilinear,
+    /// Requires mipmaps
+    Trilinear,
+}
+
+impl Filter {
+    pub fn needs_mipmaps(&self) -> bool {
+        *self == Filter::Trilinear
+    }
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Filter, String> {
+        match s {
+            "nearest" => Ok(Filter::Nearest),
+            "bilinear" => Ok(Filter::Bilinear),
+            "trilinear" => Ok(Filter::Trilinear),
+            _ => Err(format!("Invalid filter: {}", s)),
+        }
+    }
+}
+
+/// Where text gets placed on screen
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Anchor {
+    TopLeft,
+    Top,
+    TopRight,
+    Left,
+    Center,
+    Right,
+    BottomLeft,
+    Bottom,
+    BottomRight,
+}
+
+impl Anchor {
+    /// Horizontal and vertical position: 0.0 is left/top, 1.0 is
+    /// right/bottom
+    pub fn alignment(&self) -> (f32, f32) {
+        match *self {
+            Anchor::TopLeft => (0.0, 0.0),
+            Anchor::Top => (0.5, 0.0),
+            Anchor::TopRight => (1.0, 0.0),
+            Anchor::Left => (0.0, 0.5),
+            Anchor::Center => (0.5, 0.5),
+            Anchor::Right => (1.0, 0.5),
+            Anchor::BottomLeft => (0.0, 1.0),
+            Anchor::Bottom => (0.5, 1.0),
+            Anchor::BottomRight => (1.0, 1.0),
+        }
+    }
+}
+
+impl FromStr for Anchor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Anchor, String> {
+        match s {
+            "top-left" => Ok(Anchor::TopLeft),
+            "top" => Ok(Anchor::Top),
+            "top-right" => Ok(Anchor::TopRight),
+            "left" => Ok(Anchor::Left),
+            "center" => Ok(Anchor::Center),
+            "right" => Ok(Anchor::Right),
+            "bottom-left" => Ok(Anchor::BottomLeft),
+            "bottom" => Ok(Anchor::Bottom),
+            "bottom-right" => Ok(Anchor::BottomRight),
+            _ => Err(format!("Invalid anchor: {}", s)),
+        }
+    }
+}
+
+/// Picture metadata shown in the caption, one line each
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum CaptionField {
+    Filename,
+    Title,
+    Author,
+    Date,
+}
+
+impl FromStr for CaptionField {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<CaptionField, String> {
+        match s {
+            "filename" => Ok(CaptionField::Filename),
+            "title" => Ok(CaptionField::Title),
+            "author" => Ok(CaptionField::Author),
+            "date" => Ok(CaptionField::Date),
+            _ => Err(format!("Invalid caption field: {}", s)),
+        }
+    }
+}
+
+/// One slideshow window
+#[derive(Clone, Debug, Default)]
+pub struct ScreenConfig {
+    /// Own sources, takes from the shared pool if empty
+    pub sources: Vec<String>,
+    pub window: WindowOptions,
+}
+
+/// Command line settings
+///
+/// Options are given as `--name=value`, every other argument is a
+/// source. `--widget-*` options apply to the widget added last.
+///
+/// `--screen` starts another window. Window options and sources
+/// after it apply to that screen only.
+pub struct Config {
+    /// Shared pool for screens without sources of their own
+    pub sources: Vec<String>,
+    pub timing: Timing,
+    pub filter: Filter,
+    /// Maximum anisotropy, 1 disables anisotropic filtering
+    pub anisotropy: u16,
+    /// Threads fetching and decoding pictures
+    pub workers: usize,
+    /// Decoded pictures held in memory ahead of the renderer
+    pub prefetch: usize,
+    /// Empty to disable captions
+    pub caption: Vec<CaptionField>,
+    pub caption_style: TextStyle,
+    /// Fade captions in and out with their pictures
+    pub caption_fade: bool,
+    pub widgets: Vec<Widget>,
+    /// Window options if there is no `--screen`
+    pub window: WindowOptions,
+    pub screens: Vec<ScreenConfig>,
+    /// Listen address of the remote control API
+    pub remote: Option<SocketAddr>,
+    /// Required as bearer token by the remote control API
+    pub remote_token: Option<String>,
+    /// Where screenshots are saved
+    pub screenshot_dir: String,
+    /// Save the source images of the pictures shown along with screenshots
+    pub screenshot_source: bool,
+    /// Number of recent pictures checked for near duplicates, None
+    /// disables deduplication
+    pub dedup: Option<usize>,
+    /// Maximum number of differing hash bits for near duplicates
+    pub dedup_distance: u32,
+    /// Keeps the hashes across restarts
+    pub dedup_index: PathBuf,
+    /// Record what was shown
+    pub history: bool,
+    pub history_file: PathBuf,
+    /// Entries kept in the history file, older ones are dropped
+    pub history_size: usize,
+    /// Print the starred pictures as a playlist instead of showing
+    /// them
+    pub print_favorites: bool,
+    /// Continue after the picture shown last
+    pub resume: bool,
+    /// Hours within which shown pictures are skipped while there are
+    /// others, 0 to disable
+    pub recent: f64,
+    /// Starred and banned pictures
+    pub favorites_file: PathBuf,
+    /// How many times starred pictures are shown per loop
+    pub star_weight: usize,
+    /// Randomize directory and archive listings
+    pub shuffle: bool,
+    /// How many HTML pages to follow to their picture, 0 to drop
+    /// pages
+    pub html_depth: u32,
+    pub limits: Limits,
+    /// Headers and per-host credentials for all sources
+    pub request: RequestOptions,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            sources: vec![],
+            timing: Timing {
+                show: 3_000_000,
+                transition: 300_000,
+            },
+            filter: Filter::Trilinear,
+            anisotropy: 16,
+            workers: 4,
+            prefetch: 4,
+            caption: vec![],
+            caption_style: TextStyle {
+                anchor: Anchor::BottomLeft,
+                margin: 16.0,
+                size: 24.0,
+                colour: [1.0, 1.0, 1.0, 1.0],
+                background: true,
+            },
+            caption_fade: true,
+            widgets: vec![],
+            window: WindowOptions::default(),
+            screens: vec![],
+            remote: None,
+            remote_token: None,
+            screenshot_dir: ".".to_owned(),
+            screenshot_source: false,
+            dedup: None,
+            dedup_distance: 4,
+            dedup_index: cache_dir().join("hashes"),
+            history: false,
+            history_file: cache_dir().join("history.jsonl"),
+            history_size: 10_000,
+            print_favorites: false,
+            resume: false,
+            recent: 0.0,
+            favorites_file: cache_dir().join("favorites.json"),
+            star_weight: 2,
+            shuffle: false,
+            html_depth: 1,
+            limits: Limits::default(),
+            request: RequestOptions::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn from_args<I: Iterator<Item=String>>(args: I) -> Result<Config, String> {
+        let mut config = Config::default();
+        for arg in args {
+            if let Some(arg) = arg.strip_prefix("--") {
+                let mut parts = arg.splitn(2, '=');
+                let name = parts.next().unwrap();
+                let value = parts.next();
+                config.set(name, value)?;
+            } else {
+                match config.screens.last_mut() {
+                    Some(screen) => screen.sources.push(arg),
+                    None => config.sources.push(arg),
+                }
+            }
+        }
+        if config.workers < 1 || config.prefetch < 1 {
+            return Err("--workers and --prefetch must be at least 1".to_owned())
+        }
+        if config.star_weight < 1 {
+            return Err("--star-weight must be at least 1".to_owned())
+        }
+        if config.history_size < 1 {
+            return Err("--history-size must be at least 1".to_owned())
+        }
+        if (config.resume || config.recent > 0.0) && !config.history {
+            return Err("--resume and --recent need --history".to_owned())
+        }
+        if config.anisotropy < 1 {
+            return Err("--anisotropy must be at least 1".to_owned())
+        }
+        config.timing.check()
+            .map_err(|e| format!("Invalid --duration or --transition: {}", e))?;
+        if config.screens.is_empty() {
+            config.screens.push(ScreenConfig {
+                sources: vec![],
+                window: config.window.clone(),
+            });
+        }
+        Ok(config)
+    }
+
+    fn set(&mut self, name: &str, value: Option<&str>) -> Result<(), String> {
+        match name {
+            "duration" =>
+                self.timing.show = parse_seconds(name, value)?,
+            "transition" =>
+                self.timing.transition = parse_seconds(name, value)?,
+            "remote" =>
+                self.remote = Some(parse_value(name, value)?),
+            "remote-token" =>
+                self.remote_token = Some(parse_value(name, value)?),
+            "screenshot-dir" =>
+                self.screenshot_dir = parse_value(name, value)?,
+            "screenshot-source" =>
+                self.screenshot_source = parse_flag(name, value)?,
+            "dedup" =>
+                self.dedup = Some(match value {
+                    None => 1000,
+                    Some(_) => parse_value(name, value)?,
+                }),
+            "dedup-distance" =>
+                self.dedup_distance = parse_value(name, value)?,
+            "dedup-index" =>
+                self.dedup_index = PathBuf::from(parse_value::<String>(name, value)?),
+            "history" =>
+                self.history = parse_flag(name, value)?,
+            "history-file" =>
+                self.history_file = PathBuf::from(parse_value::<String>(name, value)?),
+            "history-size" =>
+                self.history_size = parse_value(name, value)?,
+            "print-favorites" =>
+                self.print_favorites = parse_flag(name, value)?,
+            "resume" =>
+                self.resume = parse_flag(name, value)?,
+            "recent" =>
+                self.recent = parse_value(name, value)?,
+            "favorites-file" =>
+                self.favorites_file = PathBuf::from(parse_value::<String>(name, value)?),
+            "star-weight" =>
+                self.star_weight = parse_value(name, value)?,
+            "shuffle" =>
+                self.shuffle = parse_flag(name, value)?,
+            "follow-html" =>
+                self.html_depth = if parse_flag(name, value)? {
+                    self.html_depth.max(1)
+                } else {
+                    0
+                },
+            "html-depth" =>
+                self.html_depth = parse_value(name, value)?,
+            "max-download" =>
+                self.limits.max_bytes = parse_megas(name, value, 1 << 20)?,
+            "max-pixels" =>
+                self.limits.max_pixels = parse_megas(name, value, 1_000_000)?,
+            "header" => {
+                let (host, header) = parse_host_header(parse_value::<String>(name, value)?.as_str())?;
+                if host.is_none() && is_credential(&header.0) {
+                    return Err(format!("Invalid value for --{}: {} needs a host, HOST=Name: value",
+                                       name, header.0))
+                }
+                self.request.headers.push((host, header));
+            },
+            "basic-auth" => {
+                let (host, credentials) = parse_host_value(name, value)?;
+                self.request.auth.push((Some(host), Auth::basic(&credentials)));
+            },
+            "bearer-auth" => {
+                let (host, token) = parse_host_value(name, value)?;
+                self.request.auth.push((Some(host), Auth::Bearer(token)));
+            },
+            "tls-ca" =>
+                self.request.tls.ca_files.push(PathBuf::from(parse_value::<String>(name, value)?)),
+            "tls-client-cert" =>
+                self.request.tls.identity = Some(PathBuf::from(parse_value::<String>(name, value)?)),
+            "tls-client-password" =>
+                self.request.tls.identity_password = parse_value(name, value)?,
+            "tls-insecure" =>
+                self.request.tls.insecure = parse_flag(name, value)?,
+            "filter" =>
+                self.filter = parse_value(name, value)?,
+            "anisotropy" =>
+                self.anisotropy = parse_value(name, value)?,
+            "workers" =>
+                self.workers = parse_value(name, value)?,
+            "prefetch" =>
+                self.prefetch = parse_value(name, value)?,
+            "caption" =>
+                self.caption = parse_list(name, value)?,
+            "caption-anchor" =>
+                self.caption_style.anchor = parse_value(name, value)?,
+            "caption-margin" =>
+                self.caption_style.margin = parse_value(name, value)?,
+            "caption-size" =>
+                self.caption_style.size = parse_value(name, value)?,
+            "caption-box" =>
+                self.caption_style.background = parse_flag(name, value)?,
+            "caption-fade" =>
+                self.caption_fade = parse_flag(name, value)?,
+            "clock" => {
+                let format = parse_time_format(name, value.unwrap_or("%H:%M"))?;
+                self.widgets.push(Widget::new(WidgetContent::Clock(format)));
+            },
+            "date" => {
+                let format = parse_time_format(name, value.unwrap_or("%A, %e %B %Y"))?;
+                self.widgets.push(Widget::new(WidgetContent::Date(format)));
+            },
+            "text" => {
+                let text = parse_value(name, value)?;
+                self.widgets.push(Widget::new(WidgetContent::Text(text)));
+            },
+            "text-file" => {
+                let path = parse_value(name, value)?;
+                self.widgets.push(Widget::new(WidgetContent::File {
+                    path,
+                    text: Arc::new(Mutex::new(String::new())),
+                }));
+            },
+            "screen" => {
+                let mut screen = ScreenConfig::default();
+                screen.window.fullscreen = true;
+                screen.window.monitor = value.map(|monitor| monitor.to_owned());
+                self.screens.push(screen);
+            },
+            "fullscreen" => {
+                let window = self.window_mut();
+                window.fullscreen = true;
+                window.monitor = value.map(|monitor| monitor.to_owned());
+            },
+            "borderless" =>
+                self.window_mut().borderless = parse_flag(name, value)?,
+            "size" =>
+                self.window_mut().size = Some(parse_pair(name, value, 'x')?),
+            "position" =>
+                self.window_mut().position = Some(parse_pair(name, value, ',')?),
+            "hide-cursor" =>
+                self.window_mut().hide_cursor = parse_flag(name, value)?,
+            "widget-anchor" =>
+                self.last_widget(name)?.style.anchor = parse_value(name, value)?,
+            "widget-margin" =>
+                self.last_widget(name)?.style.margin = parse_value(name, value)?,
+            "widget-size" =>
+                self.last_widget(name)?.style.size = parse_value(name, value)?,
+            "widget-box" =>
+                self.last_widget(name)?.style.background = parse_flag(name, value)?,
+            "widget-opacity" =>
+                self.last_widget(name)?.opacity = parse_value(name, value)?,
+            _ =>
+                return Err(format!("Unknown option --{}", name)),
+        }
+        Ok(())
+    }
+
+    fn window_mut(&mut self) -> &mut WindowOptions {
+        match self.screens.last_mut() {
+            Some(screen) => &mut screen.window,
+            None => &mut self.window,
+        }
+    }
+
+    fn last_widget(&mut self, name: &str) -> Result<&mut Widget, String> {
+        self.widgets.last_mut()
+            .ok_or_else(|| format!("Option --{} must follow a widget", name))
+    }
+}
+
+fn parse_value<T>(name: &str, value: Option<&str>) -> Result<T, String>
+    where T: FromStr, T::Err: ToString
+{
+    match value {
+        None =>
+            Err(format!("Option --{} requires a value", name)),
+        Some(value) =>
+            value.parse()
+            .map_err(|e: T::Err| format!("Invalid value for --{}: {}", name, e.to_string())),
+    }
+}
+
+/// Fractional seconds to microseconds
+pub fn parse_seconds(name: &str, value: Option<&str>) -> Result<u64, String> {
+    let seconds: f64 = parse_value(name, value)?;
+    if seconds < 0.0 {
+        return Err(format!("Invalid value for --{}: negative", name))
+    }
+    Ok((seconds * 1_000_000.0) as u64)
+}
+
+/// Fractional megabytes or megapixels
+fn parse_megas(name: &str, value: Option<&str>, mega: u64) -> Result<u64, String> {
+    let megas: f64 = parse_value(name, value)?;
+    if megas <= 0.0 {
+        return Err(format!("Invalid value for --{}: must be positive", name))
+    }
+    Ok((megas * mega as f64) as u64)
+}
+
+/// `Name: value`
+pub fn parse_header(header: &str) -> Result<(String, String), String> {
+    let mut parts = header.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some(name), Some(value)) if !name.trim().is_empty() =>
+            Ok((name.trim().to_owned(), value.trim().to_owned())),
+        _ =>
+            Err(format!("Invalid header {}, expected Name: value", header)),
+    }
+}
+
+/// `Name: value` or `HOST=Name: value`
+fn parse_host_header(header: &str) -> Result<(Option<String>, (String, String)), String> {
+    let name_end = header.find(':').unwrap_or(header.len());
+    match header[..name_end].find('=') {
+        Some(host_end) if host_end > 0 =>
+            Ok((Some(header[..host_end].trim().to_lowercase()),
+                parse_header(&header[host_end + 1..])?)),
+        _ =>
+            Ok((None, parse_header(header)?)),
+    }
+}
+
+/// `HOST=VALUE`
+fn parse_host_value(name: &str, value: Option<&str>) -> Result<(String, String), String> {
+    let value: String = parse_value(name, value)?;
+    let mut parts = value.splitn(2, '=');
+    match (parts.next(), parts.next()) {
+        (Some(host), Some(value)) if !host.is_empty() =>
+            Ok((host.to_lowercase(), value.to_owned())),
+        _ =>
+            Err(format!("Invalid value for --{}: expected HOST=...", name)),
+    }
+}
+
+/// `--name` alone means true
+fn parse_flag(name: &str, value: Option<&str>) -> Result<bool, String> {
+    match value {
+        None => Ok(true),
+        Some(_) => parse_value(name, value),
+    }
+}
+
+/// Two numbers like `1920x1080` or `0,0`
+fn parse_pair<T>(name: &str, value: Option<&str>, separator: char) -> Result<(T, T), String>
+    where T: FromStr, T::Err: ToString
+{
+    let value = value.ok_or_else(|| format!("Option --{} requires a value", name))?;
+    let mut parts = value.splitn(2, separator);
+    match (parts.next(), parts.next()) {
+        (Some(a), Some(b)) =>
+            Ok((parse_value(name, Some(a))?, parse_value(name, Some(b))?)),
+        _ =>
+            Err(format!("Invalid value for --{}: {}", name, value)),
+    }
+}
+
+/// Comma-separated, may be empty
+fn parse_list<T>(name: &str, value: Option<&str>) -> Result<Vec<T>, String>
+    where T: FromStr, T::Err: ToString
+{
+    match value {
+        None =>
+            Err(format!("Option --{} requires a value", name)),
+        Some("") =>
+            Ok(vec![]),
+        Some(value) =>
+            value.split(',')
+            .map(|item| parse_value(name, Some(item)))
+            .collect(),
+    }
+}
+
+fn parse_time_format(name: &str, format: &str) -> Result<String, String> {
+    match time::strftime(format, &time::now()) {
+        Ok(_) => Ok(format.to_owned()),
+        Err(e) => Err(format!("Invalid value for --{}: {}", name, e)),
+    }
+}
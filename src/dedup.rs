@@ -0,0 +1,182 @@
+use std::collections::VecDeque;
+use std::fs::{create_dir_all, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use source::PictureInfo;
+
+/// Thumbnails with less difference in brightness are flat
+const FLAT_RANGE: u64 = 8;
+
+/// Difference hash: one bit per horizontally adjacent pair in a 9x8
+/// grayscale thumbnail, set if brightness increases to the right
+///
+/// Takes RGBA pixels. Flat pictures get 0, they would all look alike.
+pub fn dhash(data: &[u8], width: u32, height: u32) -> u64 {
+    const COLS: u32 = 9;
+    const ROWS: u32 = 8;
+    if width < 1 || height < 1 {
+        return 0
+    }
+
+    /* Box filter into the thumbnail */
+    let mut sums = [[0u64; COLS as usize]; ROWS as usize];
+    let mut counts = [[0u64; COLS as usize]; ROWS as usize];
+    for y in 0..height {
+        let row = (y * ROWS / height) as usize;
+        for x in 0..width {
+            let col = (x * COLS / width) as usize;
+            let offset = 4 * (y * width + x) as usize;
+            let (r, g, b) = (data[offset] as u64, data[offset + 1] as u64, data[offset + 2] as u64);
+            sums[row][col] += (299 * r + 587 * g + 114 * b) / 1000;
+            counts[row][col] += 1;
+        }
+    }
+
+    let mut means = [[0u64; COLS as usize]; ROWS as usize];
+    for row in 0..ROWS as usize {
+        for col in 0..COLS as usize {
+            means[row][col] = sums[row][col] / counts[row][col].max(1);
+        }
+    }
+    let min = means.iter().flat_map(|row| row.iter()).min().cloned().unwrap_or(0);
+    let max = means.iter().flat_map(|row| row.iter()).max().cloned().unwrap_or(0);
+    if max - min < FLAT_RANGE {
+        return 0
+    }
+
+    let mut hash = 0;
+    for row in means.iter() {
+        for pair in row.windows(2) {
+            hash <<= 1;
+            if pair[1] > pair[0] {
+                hash |= 1;
+            }
+        }
+    }
+    hash
+}
+
+/// Remembers the hashes of recently shown pictures to skip near
+/// duplicates from other sources
+///
+/// The same source coming around again in the next loop is not a
+/// duplicate. Accepted hashes are appended to an index file that is
+/// read back on startup, and rewritten with only the remembered ones
+/// once another window full has been appended.
+pub struct Dedup {
+    /// Pictures remembered
+    window: usize,
+    /// Maximum number of differing bits for near duplicates
+    distance: u32,
+    recent: VecDeque<(u64, String)>,
+    index_path: PathBuf,
+    index: Option<BufWriter<File>>,
+    /// Lines appended since the index was last rewritten
+    appended: usize,
+}
+
+impl Dedup {
+    pub fn new(window: usize, distance: u32, index_path: &Path) -> Self {
+        let mut dedup = Dedup {
+            window,
+            distance,
+            recent: VecDeque::new(),
+            index_path: index_path.to_owned(),
+            index: None,
+            appended: 0,
+        };
+        dedup.load_index(index_path);
+        dedup.index = dedup.write_index(index_path);
+        dedup
+    }
+
+    fn load_index(&mut self, path: &Path) {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            /* First run */
+            Err(_) => return,
+        };
+        for line in BufReader::new(file).lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => {
+                    println!("Error reading {}: {}", path.display(), e);
+                    break
+                }
+            };
+            let mut parts = line.splitn(2, ' ');
+            let hash = parts.next().and_then(|hash| u64::from_str_radix(hash, 16).ok());
+            match (hash, parts.next()) {
+                (Some(hash), Some(source)) =>
+                    self.remember(hash, source.to_owned()),
+                _ =>
+                    println!("Ignoring invalid line in {}: {}", path.display(), line),
+            }
+        }
+        println!("Loaded {} hashes from {}", self.recent.len(), path.display());
+    }
+
+    /// Rewrites the index with only the remembered hashes, so that it
+    /// doesn't grow forever
+    fn write_index(&self, path: &Path) -> Option<BufWriter<File>> {
+        if let Some(dir) = path.parent() {
+            let _ = create_dir_all(dir);
+        }
+        let result = File::create(path).and_then(|file| {
+            let mut writer = BufWriter::new(file);
+            for &(hash, ref source) in &self.recent {
+                writeln!(writer, "{:016x} {}", hash, source)?;
+            }
+            writer.flush()?;
+            OpenOptions::new().append(true).open(path)
+        });
+        match result {
+            Ok(file) => Some(BufWriter::new(file)),
+            Err(e) => {
+                println!("Cannot write hash index {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    fn remember(&mut self, hash: u64, source: String) {
+        self.recent.retain(|(_, other)| other != &source);
+        self.recent.push_back((hash, source));
+        while self.recent.len() > self.window {
+            self.recent.pop_front();
+        }
+    }
+
+    /// Returns false for near duplicates, remembers everything else
+    pub fn check(&mut self, info: &PictureInfo) -> bool {
+        /* Flat pictures have nothing to compare */
+        if info.hash == 0 {
+            return true
+        }
+        let duplicate = self.recent.iter()
+            .find(|(hash, source)|
+                  source != &info.source &&
+                  (hash ^ info.hash).count_ones() <= self.distance);
+        if let Some((_, source)) = duplicate {
+            println!("Skipping {}, already seen as {}", info.source, source);
+            return false
+        }
+
+        self.remember(info.hash, info.source.clone());
+        if let Some(ref mut index) = self.index {
+            let result = writeln!(index, "{:016x} {}", info.hash, info.source)
+                .and_then(|_| index.flush());
+            if let Err(e) = result {
+                println!("Error writing hash index: {}", e);
+            }
+        }
+        self.appended += 1;
+        if self.appended >= self.window {
+            self.index = None;
+            self.index = self.write_index(&self.index_path);
+            self.appended = 0;
+        }
+        true
+    }
+}
@@ -0,0 +1,143 @@
+use std::collections::HashSet;
+use std::fs::{create_dir_all, rename, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use serde_json;
+
+use source::PictureInfo;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Ban {
+    /// Path or URL
+    pub source: String,
+    /// Perceptual hash, catches the same picture from other sources
+    pub hash: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Lists {
+    #[serde(default)]
+    starred: Vec<String>,
+    #[serde(default)]
+    banned: Vec<Ban>,
+}
+
+/// Starred and banned pictures, saved as JSON on every change
+pub struct Favorites {
+    path: PathBuf,
+    lists: Lists,
+    starred: HashSet<String>,
+    banned: HashSet<String>,
+    /// Maximum number of differing hash bits for banned pictures
+    distance: u32,
+}
+
+impl Favorites {
+    pub fn open(path: &Path, distance: u32) -> Self {
+        let lists = match read_lists(path) {
+            Ok(lists) => lists,
+            /* First run */
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Lists::default(),
+            Err(e) => {
+                println!("Error reading {}: {}", path.display(), e);
+                Lists::default()
+            }
+        };
+        Favorites {
+            path: path.to_owned(),
+            starred: lists.starred.iter().cloned().collect(),
+            banned: lists.banned.iter().map(|ban| ban.source.clone()).collect(),
+            lists,
+            distance,
+        }
+    }
+
+    /// Writes a file next to it and renames it over, so a crash never
+    /// leaves half a file behind
+    fn save(&self) {
+        if let Some(dir) = self.path.parent() {
+            let _ = create_dir_all(dir);
+        }
+        let tmp_path = self.path.with_extension("json.tmp");
+        let result = serde_json::to_string_pretty(&self.lists)
+            .map_err(io::Error::other)
+            .and_then(|json| {
+                let mut tmp = File::create(&tmp_path)?;
+                tmp.write_all(json.as_bytes())?;
+                tmp.sync_all()
+            })
+            .and_then(|()| rename(&tmp_path, &self.path));
+        if let Err(e) = result {
+            println!("Error writing {}: {}", self.path.display(), e);
+        }
+    }
+
+    pub fn is_starred(&self, source: &str) -> bool {
+        self.starred.contains(source)
+    }
+
+    /// In the order they were starred
+    pub fn get_starred(&self) -> &[String] {
+        &self.lists.starred
+    }
+
+    /// Returns whether it is starred now
+    pub fn toggle_star(&mut self, source: &str) -> bool {
+        let starred = if self.starred.remove(source) {
+            self.lists.starred.retain(|other| other != source);
+            false
+        } else {
+            self.starred.insert(source.to_owned());
+            self.lists.starred.push(source.to_owned());
+            true
+        };
+        self.save();
+        starred
+    }
+
+    pub fn ban(&mut self, info: &PictureInfo) {
+        if self.banned.insert(info.source.clone()) {
+            println!("Banned {}", info.source);
+            self.lists.banned.push(Ban {
+                source: info.source.clone(),
+                hash: info.hash,
+            });
+        }
+        if self.starred.remove(&info.source) {
+            self.lists.starred.retain(|other| other != &info.source);
+        }
+        self.save();
+    }
+
+    /// Checked before loading
+    pub fn is_banned_source(&self, source: &str) -> bool {
+        self.banned.contains(source)
+    }
+
+    /// Checked after decoding, by source and content
+    ///
+    /// Flat pictures are only banned by source, they all hash alike.
+    pub fn is_banned(&self, info: &PictureInfo) -> bool {
+        self.is_banned_source(&info.source) ||
+            info.hash != 0 && self.lists.banned.iter()
+            .any(|ban| (ban.hash ^ info.hash).count_ones() <= self.distance)
+    }
+}
+
+fn read_lists(path: &Path) -> io::Result<Lists> {
+    let mut json = String::new();
+    File::open(path).and_then(|mut file| file.read_to_string(&mut json))?;
+    serde_json::from_str(&json)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// `--print-favorites`: prints the starred pictures as an M3U
+/// playlist
+pub fn print(path: &Path) -> io::Result<()> {
+    let lists = read_lists(path)?;
+    println!("#EXTM3U");
+    for source in &lists.starred {
+        println!("{}", source);
+    }
+    Ok(())
+}
@@ -14,7 +14,7 @@ impl FrameCounter {
         FrameCounter {
             ticks: 0,
             last_reset: get_us(),
-            interval: interval
+            interval
         }
     }
 
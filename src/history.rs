@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+use std::fs::{create_dir_all, rename, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use serde_json;
+use time;
+
+/// One picture having been shown, a line in the history file
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Entry {
+    /// Path or URL
+    pub source: String,
+    /// Index in Config::screens
+    pub screen: usize,
+    /// Unix time when it appeared
+    pub shown_at: i64,
+    /// Microseconds on screen, not counting pauses
+    pub duration: u64,
+    #[serde(default)]
+    pub title: Option<String>,
+}
+
+/// Append-only JSONL log of everything shown
+///
+/// Read once on startup to resume and to prefer pictures not seen
+/// recently. Compacted to the latest max_entries on startup and
+/// whenever it has grown to twice that.
+pub struct History {
+    path: PathBuf,
+    file: Option<File>,
+    max_entries: usize,
+    /// Lines in the file
+    entries: usize,
+    /// Unix time each source was last shown
+    last_shown: HashMap<String, i64>,
+    /// Most recent entry per screen
+    last_entries: HashMap<usize, Entry>,
+}
+
+impl History {
+    pub fn open(path: &Path, max_entries: usize) -> Self {
+        let mut history = History {
+            path: path.to_owned(),
+            file: None,
+            max_entries,
+            entries: 0,
+            last_shown: HashMap::new(),
+            last_entries: HashMap::new(),
+        };
+        match read_entries(path) {
+            Ok(entries) => {
+                println!("Loaded {} history entries from {}", entries.len(), path.display());
+                history.entries = entries.len();
+                for entry in entries {
+                    history.remember(entry);
+                }
+            },
+            /* First run */
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => println!("Error reading {}: {}", path.display(), e),
+        }
+
+        if let Some(dir) = path.parent() {
+            let _ = create_dir_all(dir);
+        }
+        if history.entries > max_entries {
+            history.compact();
+        }
+        history.open_file();
+        history
+    }
+
+    fn open_file(&mut self) {
+        match OpenOptions::new().create(true).append(true).open(&self.path) {
+            Ok(file) => self.file = Some(file),
+            Err(e) => println!("Cannot write history {}: {}", self.path.display(), e),
+        }
+    }
+
+    /// Rewrites the file with only the latest max_entries
+    fn compact(&mut self) {
+        self.file = None;
+        let result = read_entries(&self.path).and_then(|entries| {
+            let skip = entries.len().saturating_sub(self.max_entries);
+            let tmp_path = self.path.with_extension("jsonl.tmp");
+            {
+                let mut tmp = File::create(&tmp_path)?;
+                for entry in &entries[skip..] {
+                    let line = serde_json::to_string(entry)
+                        .map_err(io::Error::other)?;
+                    writeln!(tmp, "{}", line)?;
+                }
+            }
+            rename(&tmp_path, &self.path)?;
+            Ok(entries.len() - skip)
+        });
+        match result {
+            Ok(entries) => self.entries = entries,
+            Err(e) => println!("Error compacting {}: {}", self.path.display(), e),
+        }
+    }
+
+    fn remember(&mut self, entry: Entry) {
+        self.last_shown.insert(entry.source.clone(), entry.shown_at);
+        self.last_entries.insert(entry.screen, entry);
+    }
+
+    pub fn record(&mut self, entry: Entry) {
+        if self.file.is_some() && self.entries >= 2 * self.max_entries {
+            self.compact();
+            self.open_file();
+        }
+        if let Some(ref mut file) = self.file {
+            let result = serde_json::to_string(&entry)
+                .map_err(io::Error::other)
+                .and_then(|line| writeln!(file, "{}", line));
+            match result {
+                Ok(()) => self.entries += 1,
+                Err(e) => println!("Error writing history: {}", e),
+            }
+        }
+        self.remember(entry);
+    }
+
+    /// Unix time when a source was last shown
+    pub fn last_shown(&self, source: &str) -> Option<i64> {
+        self.last_shown.get(source).cloned()
+    }
+
+    /// The latest source shown on any of these screens
+    pub fn resume_source(&self, screens: &[usize]) -> Option<String> {
+        screens.iter()
+            .filter_map(|screen| self.last_entries.get(screen))
+            .max_by_key(|entry| entry.shown_at)
+            .map(|entry| entry.source.clone())
+    }
+}
+
+pub fn read_entries(path: &Path) -> io::Result<Vec<Entry>> {
+    let file = File::open(path)?;
+    let mut entries = vec![];
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        match serde_json::from_str(&line) {
+            Ok(entry) => entries.push(entry),
+            Err(e) => println!("Ignoring invalid history line: {}", e),
+        }
+    }
+    Ok(entries)
+}
+
+/// The `history` subcommand: lists what was shown, then statistics
+pub fn print(path: &Path) -> io::Result<()> {
+    let entries = read_entries(path)?;
+    for entry in &entries {
+        let shown_at = time::at(time::Timespec::new(entry.shown_at, 0));
+        println!("{}  screen {}  {:>6.1}s  {}",
+                 time::strftime("%Y-%m-%d %H:%M:%S", &shown_at).unwrap(),
+                 entry.screen,
+                 entry.duration as f64 / 1_000_000.0,
+                 entry.source);
+    }
+
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    let mut total_duration = 0;
+    for entry in &entries {
+        *counts.entry(&entry.source).or_insert(0) += 1;
+        total_duration += entry.duration;
+    }
+    println!();
+    println!("Shown: {} times, {} distinct pictures", entries.len(), counts.len());
+    println!("Total time: {:.1} hours", total_duration as f64 / 3_600_000_000.0);
+    if !entries.is_empty() {
+        println!("Average time: {:.1}s", total_duration as f64 / entries.len() as f64 / 1_000_000.0);
+    }
+
+    let mut most_shown: Vec<(&str, usize)> = counts.into_iter().collect();
+    most_shown.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    if !most_shown.is_empty() {
+        println!("Most shown:");
+        for &(source, count) in most_shown.iter().take(10) {
+            println!("{:>6}  {}", count, source);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+
+/// Picks the picture a page is about: `og:image`, then
+/// `twitter:image`, then the largest `<img>` by its size attributes
+pub fn find_image(html: &str) -> Option<String> {
+    let metas = tags(html, "meta");
+    let meta = |keys: &[&str]| metas.iter()
+        .find(|attrs| {
+            attrs.get("property").or_else(|| attrs.get("name"))
+                .map(|key| keys.contains(&key.to_lowercase().as_str()))
+                .unwrap_or(false)
+        })
+        .and_then(|attrs| attrs.get("content"))
+        .map(|content| content.trim().to_owned())
+        .filter(|content| !content.is_empty());
+    if let Some(image) = meta(&["og:image", "og:image:url", "og:image:secure_url"]) {
+        return Some(image)
+    }
+    if let Some(image) = meta(&["twitter:image", "twitter:image:src"]) {
+        return Some(image)
+    }
+
+    let size = |attrs: &HashMap<String, String>, name: &str| attrs.get(name)
+        .and_then(|value| value.trim().trim_end_matches("px").parse::<u64>().ok())
+        .unwrap_or(0);
+    let mut largest: Option<(u64, String)> = None;
+    for attrs in tags(html, "img") {
+        let src = match attrs.get("src") {
+            Some(src) if !src.trim().is_empty() && !src.starts_with("data:") => src.trim().to_owned(),
+            _ => continue,
+        };
+        let area = size(&attrs, "width") * size(&attrs, "height");
+        if largest.as_ref().map(|&(largest_area, _)| area > largest_area).unwrap_or(true) {
+            largest = Some((area, src));
+        }
+    }
+    largest.map(|(_, src)| src)
+}
+
+/// Attributes of every tag with a name, found by scanning for `<name`
+///
+/// Good enough for `<meta>` and `<img>`, which have no content.
+fn tags(html: &str, name: &str) -> Vec<HashMap<String, String>> {
+    let lower = html.to_ascii_lowercase();
+    let needle = format!("<{}", name);
+    let mut result = vec![];
+    let mut offset = 0;
+    while let Some(start) = lower[offset..].find(&needle) {
+        let start = offset + start + needle.len();
+        /* Not a longer tag name like <image> */
+        let next = lower[start..].chars().next();
+        if next.map(|c| c.is_alphanumeric()).unwrap_or(true) {
+            offset = start;
+            continue
+        }
+        let end = match lower[start..].find('>') {
+            Some(end) => start + end,
+            None => break,
+        };
+        result.push(parse_attributes(&html[start..end]));
+        offset = end;
+    }
+    result
+}
+
+fn parse_attributes(s: &str) -> HashMap<String, String> {
+    let mut attrs = HashMap::new();
+    let mut chars = s.chars().peekable();
+    loop {
+        while chars.peek().map(|c| c.is_whitespace() || *c == '/').unwrap_or(false) {
+            chars.next();
+        }
+        let mut name = String::new();
+        while let Some(&c) = chars.peek() {
+            if c.is_whitespace() || c == '=' || c == '/' {
+                break
+            }
+            name.push(c);
+            chars.next();
+        }
+        if name.is_empty() {
+            return attrs
+        }
+        while chars.peek().map(|c| c.is_whitespace()).unwrap_or(false) {
+            chars.next();
+        }
+        let mut value = String::new();
+        if chars.peek() == Some(&'=') {
+            chars.next();
+            while chars.peek().map(|c| c.is_whitespace()).unwrap_or(false) {
+                chars.next();
+            }
+            match chars.peek().cloned() {
+                Some(quote) if quote == '"' || quote == '\'' => {
+                    chars.next();
+                    for c in chars.by_ref() {
+                        if c == quote {
+                            break
+                        }
+                        value.push(c);
+                    }
+                },
+                _ =>
+                    while let Some(&c) = chars.peek() {
+                        if c.is_whitespace() {
+                            break
+                        }
+                        value.push(c);
+                        chars.next();
+                    },
+            }
+        }
+        attrs.insert(name.to_lowercase(), decode_entities(&value));
+    }
+}
+
+/// Only what shows up in URLs
+fn decode_entities(s: &str) -> String {
+    s.replace("&amp;", "&")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+}
@@ -1,34 +1,518 @@
-use std::io::{Read, Error, ErrorKind};
-use futures::Stream;
-use tokio_core::reactor::Core;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::env;
+use std::io::{Read, Write, Error, ErrorKind};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::channel;
+use std::thread::{self, Thread};
+use std::time::Duration;
+use base64;
+use futures::{future, executor, Async, Future, Poll, Stream};
+use futures::executor::Notify;
+use futures::future::Loop;
+use futures::stream::Wait;
+use futures::sync::oneshot;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{Core, Handle, Remote};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::io::{read, write_all};
+use tokio_tls::{TlsConnector, TlsStream};
 use hyper;
-use hyper_tls::HttpsConnector;
-
-pub fn get(uri: &hyper::Uri) -> Result<Response, hyper::Error> {
-    let mut core = Core::new()?;
-    let handle = core.handle();
-    match uri.scheme() {
-        Some("http") => {
-            let client = hyper::Client::new(&handle);
-            let res = core.run(client.get(uri.clone()))?;
-            Ok(Response { res, core })
-        },
-        Some("https") => {
-            let client = hyper::Client::configure()
-                .connector(HttpsConnector::new(1, &handle)
-                           .map_err(|_| hyper::Error::Incomplete)?)
-                .build(&handle);
-            let res = core.run(client.get(uri.clone()))?;
-            Ok(Response { res, core })
-        },
-        _ =>
-            Err(hyper::Error::Version),
+use hyper::client::{HttpConnector, Service};
+use hyper::header::{Authorization, Basic, Bearer, ContentLength, ProxyAuthorization};
+use url::{Url, percent_encoding};
+
+use netrc;
+use tls::TlsOptions;
+
+/// Credentials for one host
+#[derive(Clone, Debug)]
+pub enum Auth {
+    Basic {
+        username: String,
+        password: Option<String>,
+    },
+    Bearer(String),
+}
+
+impl Auth {
+    /// `USER:PASSWORD`
+    pub fn basic(credentials: &str) -> Self {
+        let mut parts = credentials.splitn(2, ':');
+        Auth::Basic {
+            username: parts.next().unwrap_or("").to_owned(),
+            password: parts.next().map(|password| password.to_owned()),
+        }
+    }
+}
+
+/// Headers and credentials sent with the requests of a source
+#[derive(Clone, Debug, Default)]
+pub struct RequestOptions {
+    /// Only sent to their host, or to every host while the host is
+    /// None unless they carry credentials. Later ones replace earlier
+    /// ones of the same name
+    pub headers: Vec<(Option<String>, (String, String))>,
+    /// Only sent to their host, never while the host is None
+    pub auth: Vec<(Option<String>, Auth)>,
+    pub tls: TlsOptions,
+}
+
+impl RequestOptions {
+    /// Inner headers replace outer ones, inner credentials are tried
+    /// first
+    pub fn or(&self, outer: &RequestOptions) -> RequestOptions {
+        RequestOptions {
+            headers: outer.headers.iter().chain(self.headers.iter()).cloned().collect(),
+            auth: self.auth.iter().chain(outer.auth.iter()).cloned().collect(),
+            tls: self.tls.or(&outer.tls),
+        }
+    }
+
+    /// Restricts headers and credentials given without a host to the
+    /// host of a URL
+    pub fn bind_host(&mut self, url: &str) {
+        let host = url.parse::<hyper::Uri>().ok()
+            .and_then(|uri| uri.host().map(|host| host.to_lowercase()));
+        let hosts = self.headers.iter_mut().map(|(header_host, _)| header_host)
+            .chain(self.auth.iter_mut().map(|(auth_host, _)| auth_host));
+        for header_host in hosts {
+            if header_host.is_none() {
+                *header_host = host.clone();
+            }
+        }
+    }
+
+    /// Headers for the host, then credentials for the host unless
+    /// there already is an Authorization header, falling back to netrc
+    pub fn apply(&self, req: &mut hyper::Request) {
+        let host = match req.uri().host() {
+            Some(host) => host.to_lowercase(),
+            None => return,
+        };
+        for (header_host, (name, value)) in &self.headers {
+            let send = match *header_host {
+                Some(ref header_host) => *header_host == host,
+                None => !is_credential(name),
+            };
+            if send {
+                req.headers_mut().set_raw(name.clone(), value.clone());
+            }
+        }
+        if req.headers().get_raw("Authorization").is_some() {
+            return
+        }
+        let auth = self.auth.iter()
+            .find(|&(auth_host, _)| auth_host.as_ref() == Some(&host))
+            .map(|(_, auth)| auth.clone())
+            .or_else(|| netrc::lookup(&host)
+                     .map(|(username, password)| Auth::Basic { username, password }));
+        match auth {
+            Some(Auth::Basic { username, password }) =>
+                req.headers_mut().set(Authorization(Basic { username, password })),
+            Some(Auth::Bearer(token)) =>
+                req.headers_mut().set(Authorization(Bearer { token })),
+            None => (),
+        }
+    }
+}
+
+/// Headers that must not leak to other hosts
+pub fn is_credential(name: &str) -> bool {
+    ["Authorization", "Cookie", "Proxy-Authorization"].iter()
+        .any(|credential| credential.eq_ignore_ascii_case(name))
+}
+
+pub fn get(uri: &hyper::Uri, options: &RequestOptions) -> Result<Response, hyper::Error> {
+    get_async(uri, options).wait()
+}
+
+pub fn get_async(uri: &hyper::Uri, options: &RequestOptions) -> Box<dyn Future<Item=Response, Error=hyper::Error> + Send> {
+    let mut req = hyper::Request::new(hyper::Method::Get, uri.clone());
+    options.apply(&mut req);
+    request_async(req, &options.tls)
+}
+
+/// `http_proxy` or `https_proxy`, unless `no_proxy` lists the host
+/// or a domain it is in
+fn proxy_for(uri: &hyper::Uri) -> Option<Url> {
+    let var = |name: &str| env::var(name)
+        .or_else(|_| env::var(name.to_uppercase()))
+        .ok()
+        .filter(|value| !value.is_empty());
+    let host = match uri.host() {
+        Some(host) => host.to_lowercase(),
+        None => return None,
+    };
+    let proxy = match uri.scheme() {
+        Some("http") => var("http_proxy"),
+        Some("https") => var("https_proxy"),
+        _ => None,
+    };
+    let proxy = proxy?;
+    let no_proxy = var("no_proxy").unwrap_or_default();
+    let excluded = no_proxy.split(',')
+        .map(|pattern| pattern.trim().trim_start_matches('.').to_lowercase())
+        .filter(|pattern| !pattern.is_empty())
+        .any(|pattern| pattern == "*" ||
+             host == pattern ||
+             host.ends_with(&format!(".{}", pattern)));
+    if excluded {
+        return None
+    }
+    let proxy = if proxy.contains("://") {
+        proxy
+    } else {
+        format!("http://{}", proxy)
+    };
+    match Url::parse(&proxy) {
+        Ok(url) => Some(url),
+        Err(e) => {
+            println!("Invalid proxy {}: {}", proxy, e);
+            None
+        }
+    }
+}
+
+/// A proxy's address and the credentials from its URL
+struct Proxy {
+    uri: hyper::Uri,
+    authorization: Option<Basic>,
+}
+
+impl Proxy {
+    fn from_url(url: &Url) -> Result<Self, hyper::Error> {
+        let host = url.host_str().unwrap_or("");
+        let port = url.port_or_known_default().unwrap_or(80);
+        let uri = format!("http://{}:{}", host, port).parse()?;
+        let authorization = if url.username().is_empty() {
+            None
+        } else {
+            let decode = |s: &str| percent_encoding::percent_decode(s.as_bytes())
+                .decode_utf8_lossy()
+                .into_owned();
+            Some(Basic {
+                username: decode(url.username()),
+                password: url.password().map(&decode),
+            })
+        };
+        Ok(Proxy { uri, authorization })
+    }
+
+    /// Asks for a tunnel to host:port
+    fn connect_request(&self, host: &str, port: u16) -> Vec<u8> {
+        let mut head = format!("CONNECT {0}:{1} HTTP/1.1\r\nHost: {0}:{1}\r\n", host, port);
+        if let Some(Basic { ref username, ref password }) = self.authorization {
+            let credentials = format!("{}:{}", username, password.as_ref().map(|p| p.as_str()).unwrap_or(""));
+            head.push_str(&format!("Proxy-Authorization: Basic {}\r\n", base64::encode(&credentials)));
+        }
+        head.push_str("\r\n");
+        head.into_bytes()
+    }
+}
+
+/// Plain TCP, or TLS on top of it
+enum Connection {
+    Http(TcpStream),
+    Https(TlsStream<TcpStream>),
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        match *self {
+            Connection::Http(ref mut stream) => stream.read(buf),
+            Connection::Https(ref mut stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        match *self {
+            Connection::Http(ref mut stream) => stream.write(buf),
+            Connection::Https(ref mut stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        match *self {
+            Connection::Http(ref mut stream) => stream.flush(),
+            Connection::Https(ref mut stream) => stream.flush(),
+        }
+    }
+}
+
+impl AsyncRead for Connection {}
+
+impl AsyncWrite for Connection {
+    fn shutdown(&mut self) -> Poll<(), Error> {
+        match *self {
+            Connection::Http(ref mut stream) => stream.shutdown(),
+            Connection::Https(ref mut stream) => stream.shutdown(),
+        }
+    }
+}
+
+/// Connects directly or through the proxy for the URI, tunnelling
+/// HTTPS with CONNECT
+struct Connector {
+    http: HttpConnector,
+    tls: TlsConnector,
+}
+
+impl Service for Connector {
+    type Request = hyper::Uri;
+    type Response = Connection;
+    type Error = Error;
+    type Future = Box<dyn Future<Item=Connection, Error=Error>>;
+
+    fn call(&self, uri: hyper::Uri) -> Self::Future {
+        let host = match uri.host() {
+            Some(host) => host.to_owned(),
+            None => return Box::new(future::err(Error::new(ErrorKind::InvalidInput, "URI without host"))),
+        };
+        let https = uri.scheme() == Some("https");
+        let port = uri.port().unwrap_or(if https { 443 } else { 80 });
+        let proxy = match proxy_for(&uri).map(|url| Proxy::from_url(&url)) {
+            Some(Ok(proxy)) => Some(proxy),
+            Some(Err(e)) => return Box::new(future::err(Error::new(ErrorKind::InvalidInput, format!("Invalid proxy: {}", e)))),
+            None => None,
+        };
+        let tcp = match proxy {
+            Some(ref proxy) => self.http.call(proxy.uri.clone()),
+            None => self.http.call(uri),
+        };
+        if !https {
+            return Box::new(tcp.map(Connection::Http))
+        }
+
+        let tcp: Box<dyn Future<Item=TcpStream, Error=Error>> = match proxy {
+            Some(ref proxy) => {
+                let request = proxy.connect_request(&host, port);
+                Box::new(tcp.and_then(move |tcp| tunnel(tcp, request)))
+            },
+            None => Box::new(tcp),
+        };
+        let tls = self.tls.clone();
+        Box::new(tcp.and_then(move |tcp| {
+            tls.connect(&host, tcp)
+                .map(Connection::Https)
+                .map_err(Error::other)
+        }))
     }
 }
 
+/// Sends the CONNECT request and reads the response head byte by
+/// byte, so that nothing of the tunnelled stream is consumed
+fn tunnel(tcp: TcpStream, request: Vec<u8>) -> Box<dyn Future<Item=TcpStream, Error=Error>> {
+    Box::new(write_all(tcp, request).and_then(|(tcp, _)| {
+        future::loop_fn((tcp, vec![]), |(tcp, mut head): (TcpStream, Vec<u8>)| {
+            read(tcp, [0u8]).and_then(move |(tcp, byte, len)| {
+                if len == 0 {
+                    return Err(Error::new(ErrorKind::UnexpectedEof, "Proxy closed the connection"))
+                }
+                head.push(byte[0]);
+                if !head.ends_with(b"\r\n\r\n") {
+                    return if head.len() > MAX_PROXY_HEAD {
+                        Err(Error::new(ErrorKind::InvalidData, "Proxy response too long"))
+                    } else {
+                        Ok(Loop::Continue((tcp, head)))
+                    }
+                }
+                /* HTTP/1.1 200 Connection established */
+                if head.split(|&b| b == b' ').nth(1) == Some(b"200") {
+                    Ok(Loop::Break(tcp))
+                } else {
+                    let status = String::from_utf8_lossy(&head);
+                    Err(Error::other(format!("Proxy refused: {}", status.lines().next().unwrap_or(""))))
+                }
+            })
+        })
+    }))
+}
+
+const MAX_PROXY_HEAD: usize = 8192;
+
+fn tls_error(e: String) -> hyper::Error {
+    hyper::Error::Io(Error::other(e))
+}
+
+lazy_static! {
+    /// All connections live on one reactor thread, every request
+    /// fails if it could not be started
+    static ref REACTOR: Result<Remote, String> = spawn_reactor();
+}
+
+fn spawn_reactor() -> Result<Remote, String> {
+    let (remote_tx, remote_rx) = channel();
+    thread::Builder::new()
+        .name("reactor".to_owned())
+        .spawn(move|| {
+            let mut core = match Core::new() {
+                Ok(core) => core,
+                Err(e) => {
+                    let _ = remote_tx.send(Err(format!("Cannot start reactor: {}", e)));
+                    return
+                }
+            };
+            let _ = remote_tx.send(Ok(core.remote()));
+            loop {
+                core.turn(None);
+            }
+        })
+        .map_err(|e| format!("Cannot start reactor thread: {}", e))?;
+    remote_rx.recv()
+        .map_err(|_| "Reactor thread stopped".to_owned())?
+}
+
+/// Sends a request that may carry any method, headers and body, and
+/// waits for the response headers
+pub fn request(req: hyper::Request, tls: &TlsOptions) -> Result<Response, hyper::Error> {
+    request_async(req, tls).wait()
+}
+
+/// Sends a request on the reactor thread
+///
+/// Goes through the proxy from the environment, if any.
+///
+/// Dropping the returned future before the response arrives drops
+/// the request, closing its connection.
+pub fn request_async(req: hyper::Request, tls: &TlsOptions) -> Box<dyn Future<Item=Response, Error=hyper::Error> + Send> {
+    let remote = match *REACTOR {
+        Ok(ref remote) => remote,
+        Err(ref e) => return Box::new(future::err(hyper::Error::Io(Error::other(e.clone())))),
+    };
+    let (res_tx, res_rx) = oneshot::channel();
+    let tls = tls.clone();
+    remote.spawn(move |handle| {
+        let res = match send(req, &tls, handle) {
+            Ok(res) => res,
+            Err(e) => Box::new(future::err(e)),
+        };
+        Deliver {
+            res,
+            res_tx: Some(res_tx),
+        }
+    });
+    Box::new(res_rx.then(|res| match res {
+        Ok(res) => res,
+        /* The reactor dropped the request */
+        Err(_) => Err(hyper::Error::Incomplete),
+    }))
+}
+
+/// Passes a response from the reactor to the thread waiting for it,
+/// or drops the request once nobody is waiting anymore
+struct Deliver {
+    res: Box<dyn Future<Item=hyper::Response, Error=hyper::Error>>,
+    res_tx: Option<oneshot::Sender<Result<Response, hyper::Error>>>,
+}
+
+impl Future for Deliver {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        let res = match self.res.poll() {
+            Ok(Async::NotReady) => {
+                let cancelled = self.res_tx.as_mut()
+                    .map(|res_tx| res_tx.poll_cancel() == Ok(Async::Ready(())))
+                    .unwrap_or(true);
+                return Ok(if cancelled { Async::Ready(()) } else { Async::NotReady })
+            },
+            Ok(Async::Ready(res)) => Ok(Response { res }),
+            Err(e) => Err(e),
+        };
+        if let Some(res_tx) = self.res_tx.take() {
+            let _ = res_tx.send(res);
+        }
+        Ok(Async::Ready(()))
+    }
+}
+
+/// Wakes the thread blocked in wait()
+struct ThreadNotify(Thread);
+
+impl Notify for ThreadNotify {
+    fn notify(&self, _id: usize) {
+        self.0.unpark();
+    }
+}
+
+/// How often wait() looks at its cancel flag
+const CANCEL_POLL_INTERVAL: u64 = 100;
+
+/// Waits for a future on the current thread, giving up on it once
+/// cancel is set
+pub fn wait<F: Future<Error=hyper::Error>>(future: F, cancel: &AtomicBool) -> Result<F::Item, hyper::Error> {
+    let notify = Arc::new(ThreadNotify(thread::current()));
+    let mut future = executor::spawn(future);
+    loop {
+        if let Async::Ready(item) = future.poll_future_notify(&notify, 0)? {
+            return Ok(item)
+        }
+        if cancel.load(Ordering::Relaxed) {
+            return Err(cancelled())
+        }
+        thread::park_timeout(Duration::from_millis(CANCEL_POLL_INTERVAL));
+    }
+}
+
+fn cancelled() -> hyper::Error {
+    hyper::Error::Io(Error::other("Cancelled"))
+}
+
+fn send(mut req: hyper::Request, tls: &TlsOptions, handle: &Handle) -> Result<Box<dyn Future<Item=hyper::Response, Error=hyper::Error>>, hyper::Error> {
+    let https = match req.uri().scheme() {
+        Some("http") => false,
+        Some("https") => true,
+        _ => return Err(hyper::Error::Version),
+    };
+    let proxy = match proxy_for(req.uri()) {
+        Some(url) => Some(Proxy::from_url(&url)?),
+        None => None,
+    };
+    /* Plain HTTP goes to the proxy as an absolute URI */
+    if let (false, Some(proxy)) = (https, proxy.as_ref()) {
+        if let Some(ref authorization) = proxy.authorization {
+            req.headers_mut().set(ProxyAuthorization(authorization.clone()));
+        }
+        req.set_proxy(true);
+    }
+
+    let client = client(tls, handle)?;
+    Ok(Box::new(client.request(req)))
+}
+
+thread_local! {
+    /// Clients of the reactor thread by TLS options, so that their
+    /// connections are kept alive between requests
+    static CLIENTS: RefCell<HashMap<TlsOptions, hyper::Client<Connector>>> = RefCell::new(HashMap::new());
+}
+
+/// Built on first use on the reactor thread, shared afterwards
+fn client(tls: &TlsOptions, handle: &Handle) -> Result<hyper::Client<Connector>, hyper::Error> {
+    CLIENTS.with(|clients| {
+        if let Some(client) = clients.borrow().get(tls) {
+            return Ok(client.clone())
+        }
+        let mut http = HttpConnector::new(1, handle);
+        http.enforce_http(false);
+        let connector = Connector {
+            http,
+            tls: TlsConnector::from(tls.connector().map_err(tls_error)?),
+        };
+        let client = hyper::Client::configure()
+            .connector(connector)
+            .build(handle);
+        clients.borrow_mut().insert(tls.clone(), client.clone());
+        Ok(client)
+    })
+}
+
 pub struct Response {
     res: hyper::Response,
-    core: Core,
 }
 
 impl Response {
@@ -40,36 +524,130 @@ impl Response {
         self.res.headers()
     }
 
+    /// Blocks on each chunk, for use outside of the reactor
     pub fn body(self) -> Body {
         Body {
-            body: Some(self.res.body()),
-            core: self.core,
+            chunks: self.res.body().wait(),
             buf_offset: 0,
             buf: vec![],
+            limit: None,
+            received: 0,
+        }
+    }
+
+    /// A body that fails to read beyond max_bytes, refused right away
+    /// if the Content-Length is already too large
+    pub fn body_limited(self, max_bytes: u64) -> Result<Body, Error> {
+        match self.headers().get::<ContentLength>() {
+            Some(&ContentLength(len)) if len > max_bytes =>
+                return Err(too_large(max_bytes)),
+            _ => (),
+        }
+        let mut body = self.body();
+        body.limit = Some(max_bytes);
+        Ok(body)
+    }
+
+    /// Collects the whole body without blocking, failing beyond
+    /// max_bytes or once cancel is set
+    pub fn read_body(self, max_bytes: u64, cancel: Arc<AtomicBool>) -> Box<dyn Future<Item=Vec<u8>, Error=hyper::Error> + Send> {
+        Box::new(self.read_start(0, max_bytes)
+                 .and_then(move |partial| partial.read_rest(max_bytes, cancel)))
+    }
+
+    /// Reads at least len bytes, unless the body is shorter, without
+    /// blocking
+    ///
+    /// Fails right away if the Content-Length is beyond max_bytes.
+    pub fn read_start(self, len: usize, max_bytes: u64) -> Box<dyn Future<Item=Partial, Error=hyper::Error> + Send> {
+        match self.headers().get::<ContentLength>() {
+            Some(&ContentLength(content_len)) if content_len > max_bytes =>
+                return Box::new(future::err(hyper::Error::Io(too_large(max_bytes)))),
+            _ => (),
         }
+        let start = Partial {
+            data: vec![],
+            body: Some(self.res.body()),
+        };
+        Box::new(future::loop_fn(start, move |mut partial| {
+            let body = match partial.body.take() {
+                Some(body) if partial.data.len() < len => body,
+                body => {
+                    partial.body = body;
+                    return future::Either::A(future::ok(Loop::Break(partial)))
+                },
+            };
+            future::Either::B(body.into_future()
+                .map_err(|(e, _)| e)
+                .map(move |(chunk, body)| match chunk {
+                    Some(chunk) => {
+                        partial.data.extend_from_slice(&chunk);
+                        partial.body = Some(body);
+                        Loop::Continue(partial)
+                    },
+                    /* Ended */
+                    None => Loop::Break(partial),
+                }))
+        }))
     }
 }
 
-pub struct Body {
+/// A body of which the start has been read
+pub struct Partial {
+    data: Vec<u8>,
+    /// None once it ended
     body: Option<hyper::Body>,
-    core: Core,
+}
+
+impl Partial {
+    pub fn start(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Collects the rest of the body, failing beyond max_bytes or once
+    /// cancel is set
+    pub fn read_rest(self, max_bytes: u64, cancel: Arc<AtomicBool>) -> Box<dyn Future<Item=Vec<u8>, Error=hyper::Error> + Send> {
+        if self.data.len() as u64 > max_bytes {
+            return Box::new(future::err(hyper::Error::Io(too_large(max_bytes))))
+        }
+        let body = match self.body {
+            Some(body) => body,
+            None => return Box::new(future::ok(self.data)),
+        };
+        Box::new(body.fold(self.data, move |mut data, chunk| {
+            if cancel.load(Ordering::Relaxed) {
+                return Err(cancelled())
+            }
+            data.extend_from_slice(&chunk);
+            if data.len() as u64 > max_bytes {
+                Err(hyper::Error::Io(too_large(max_bytes)))
+            } else {
+                Ok(data)
+            }
+        }))
+    }
+}
+
+fn too_large(max_bytes: u64) -> Error {
+    Error::other(format!("Response larger than {} bytes", max_bytes))
+}
+
+pub struct Body {
+    chunks: Wait<hyper::Body>,
     buf_offset: usize,
     buf: Vec<u8>,
+    limit: Option<u64>,
+    received: u64,
 }
 
 impl Body {
     fn recv_next(&mut self) -> Result<Vec<u8>, hyper::Error> {
-        let body_future = self.body.take()
-            .expect("body")
-            .into_future();
-        let (next_item, body) = self.core.run(body_future)
-            .map_err(|(e, _body)| e)?;
-        self.body = Some(body);
-        match next_item {
+        match self.chunks.next() {
             None => Ok(vec![]),
-            Some(ref buf) if buf.len() == 0 =>
+            Some(Err(e)) => Err(e),
+            Some(Ok(ref buf)) if buf.is_empty() =>
                 self.recv_next(),
-            Some(buf) =>
+            Some(Ok(buf)) =>
                 Ok(buf.to_vec()),
         }
     }
@@ -78,11 +656,17 @@ impl Body {
 
 impl Read for Body {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
-        let err_mapper = |e| Error::new(ErrorKind::Other, e);
+        let err_mapper = |e| Error::other(e);
         if self.buf_offset >= self.buf.len() {
             self.buf = self.recv_next()
                 .map_err(err_mapper)?;
             self.buf_offset = 0;
+            self.received += self.buf.len() as u64;
+            match self.limit {
+                Some(limit) if self.received > limit =>
+                    return Err(too_large(limit)),
+                _ => (),
+            }
         }
 
         let buf_left = self.buf.len() - self.buf_offset;
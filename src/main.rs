@@ -3,38 +3,209 @@ extern crate glium;
 extern crate image;
 extern crate time;
 extern crate futures;
+extern crate futures_cpupool;
 extern crate tokio_core;
 extern crate hyper;
-extern crate hyper_tls;
 extern crate treexml;
 extern crate num_iter;
+extern crate jpeg_decoder;
+extern crate rusttype;
+extern crate exif;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate url;
+extern crate zip;
+extern crate tar;
+extern crate flate2;
+extern crate rand;
+extern crate sha2;
+extern crate hmac;
+extern crate native_tls;
+extern crate tokio_io;
+extern crate tokio_tls;
+extern crate base64;
+extern crate dirs;
+#[macro_use]
+extern crate lazy_static;
+#[cfg(test)]
+extern crate tempfile;
 
-use std::sync::mpsc::{sync_channel};
-use std::thread;
+use std::net::TcpListener;
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
+use glium::glutin::{EventsLoop, Event};
+use std::process::exit;
 
+mod config;
 mod render;
+mod tiles;
+mod text;
+mod widgets;
+mod window;
 mod util;
 mod http;
 mod source;
+mod prefetch;
 mod frame_counter;
+mod remote;
+mod screenshot;
+mod dedup;
+mod history;
+mod favorites;
+mod playlist;
+mod archive;
+mod pipe;
+mod s3;
+mod webdav;
+mod html;
+mod sniff;
+mod netrc;
+mod tls;
+#[cfg(test)]
+mod testing;
 
+use config::Config;
 use render::*;
 use source::Loader;
+use remote::Remote;
+use dedup::Dedup;
+use history::History;
+use favorites::Favorites;
 use frame_counter::FrameCounter;
 
 fn main() {
-    let (source_tx, source_rx) = sync_channel(2);
-    let mut renderer = Renderer::new(source_rx);
-    thread::spawn(move|| {
-        let filenames: Vec<String> = std::env::args()
-            .skip(1)
-            .collect();
-        Loader::new(source_tx).run_loop(filenames);
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    /* `history` prints the history instead of showing pictures */
+    let print_history = args.first().map(|arg| arg == "history").unwrap_or(false);
+    if print_history {
+        args.remove(0);
+    }
+    let config = match Config::from_args(args.into_iter()) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("{}", e);
+            exit(1)
+        }
+    };
+
+    if print_history || config.print_favorites {
+        let (path, result) = if print_history {
+            (&config.history_file, history::print(&config.history_file))
+        } else {
+            (&config.favorites_file, favorites::print(&config.favorites_file))
+        };
+        if let Err(e) = result {
+            println!("Cannot read {}: {}", path.display(), e);
+            exit(1)
+        }
+        return
+    }
+
+    /* Fail early on unreadable certificates */
+    if !config.request.tls.is_default() {
+        if let Err(e) = config.request.tls.connector() {
+            println!("{}", e);
+            exit(1)
+        }
+    }
+    if config.request.tls.insecure {
+        tls::warn_insecure();
+    }
+    widgets::watch_files(&config.widgets);
+
+    let mut events_loop = EventsLoop::new();
+    let mut shared_loader = None;
+    let dedup = config.dedup.map(|window| {
+        Arc::new(Mutex::new(Dedup::new(window, config.dedup_distance, &config.dedup_index)))
     });
+    let play_history = if config.history {
+        Some(Arc::new(Mutex::new(History::open(&config.history_file, config.history_size))))
+    } else {
+        None
+    };
+    let favorites = Arc::new(Mutex::new(Favorites::open(&config.favorites_file, config.dedup_distance)));
+    /* Where each loader left off on the screens it serves */
+    let resume_source = |screens: &[usize]| if config.resume {
+        play_history.as_ref()
+            .and_then(|play_history| play_history.lock().unwrap().resume_source(screens))
+    } else {
+        None
+    };
+    let shared_screens: Vec<usize> = config.screens.iter()
+        .enumerate()
+        .filter(|&(_, screen)| screen.sources.is_empty())
+        .map(|(i, _)| i)
+        .collect();
+    let mut loaders = vec![];
+    let mut renderers: Vec<Renderer> = vec![];
+    for (i, screen) in config.screens.iter().enumerate() {
+        let loader = if screen.sources.is_empty() {
+            /* Each picture goes to only one screen */
+            shared_loader.get_or_insert_with(|| Loader::spawn(&config, config.sources.clone(), dedup.clone(), play_history.clone(), favorites.clone(), resume_source(&shared_screens)))
+                .clone()
+        } else {
+            Loader::spawn(&config, screen.sources.clone(), dedup.clone(), play_history.clone(), favorites.clone(), resume_source(&[i]))
+        };
+        let vsync = renderers.is_empty();
+        renderers.push(Renderer::new(&config, i, vsync, &events_loop, &loader, play_history.clone(), favorites.clone()));
+        loaders.push(loader);
+    }
+
+    let (commands_tx, commands_rx) = channel();
+    let (jumps_tx, jumps_rx) = channel();
+    let status = Arc::new(Mutex::new(vec![ScreenStatus::default(); renderers.len()]));
+    if let Some(addr) = config.remote {
+        match TcpListener::bind(addr) {
+            Ok(listener) =>
+                Remote::new(config.remote_token.clone(), commands_tx, status.clone(), loaders, favorites.clone())
+                .spawn(listener),
+            Err(e) =>
+                println!("Cannot start remote control on {}: {}", addr, e),
+        }
+    }
 
     let mut counter = FrameCounter::new(1_000_000);
-    while renderer.update() {
-        renderer.render();
+    while !renderers.is_empty() {
+        let mut events = vec![];
+        events_loop.poll_events(|ev| events.push(ev));
+        let mut closed = vec![];
+        for ev in events {
+            if let Event::WindowEvent { event, window_id } = ev {
+                for renderer in renderers.iter_mut()
+                    .filter(|renderer| renderer.window_id() == Some(window_id)) {
+                        match renderer.handle_event(event.clone(), &events_loop) {
+                            Close::Nothing => (),
+                            Close::Window => closed.push(renderer.screen()),
+                            Close::All => return,
+                        }
+                    }
+            }
+        }
+        if !closed.is_empty() {
+            /* Only that window goes away, the others keep showing */
+            renderers.retain(|renderer| !closed.contains(&renderer.screen()));
+            if let Some(renderer) = renderers.first_mut() {
+                renderer.enable_vsync(&events_loop);
+            }
+            for &screen in &closed {
+                status.lock().unwrap()[screen] = ScreenStatus::default();
+            }
+        }
+        remote::dispatch(&commands_rx, &mut renderers, &jumps_tx);
+        while let Ok((screens, loaded)) = jumps_rx.try_recv() {
+            for renderer in renderers.iter_mut()
+                .filter(|renderer| screens.contains(&renderer.screen())) {
+                    renderer.jump(loaded.clone());
+                }
+        }
+
+        for renderer in &mut renderers {
+            renderer.update();
+            renderer.render();
+            remote::publish_status(&status, renderer);
+        }
         counter.tick();
     }
 }
@@ -0,0 +1,75 @@
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+use dirs;
+
+/// `$NETRC`, or `.netrc` in the home directory
+fn path() -> Option<PathBuf> {
+    match env::var("NETRC") {
+        Ok(path) => Some(PathBuf::from(path)),
+        Err(_) => dirs::home_dir().map(|home| home.join(".netrc")),
+    }
+}
+
+enum Entry {
+    Other,
+    Host,
+    Default,
+}
+
+/// Login and password for a host, falling back to the `default`
+/// entry
+pub fn lookup(host: &str) -> Option<(String, Option<String>)> {
+    let mut text = String::new();
+    match path().and_then(|path| File::open(path).ok()) {
+        Some(mut file) =>
+            if file.read_to_string(&mut text).is_err() {
+                return None
+            },
+        None =>
+            return None,
+    }
+
+    let mut found = None;
+    let mut default = None;
+    /* Which entry the login and password tokens belong to */
+    let mut current = Entry::Other;
+    let mut tokens = text.split_whitespace();
+    while let Some(token) = tokens.next() {
+        match token {
+            "machine" =>
+                current = if tokens.next() == Some(host) && found.is_none() {
+                    found = Some((None, None));
+                    Entry::Host
+                } else {
+                    Entry::Other
+                },
+            "default" => {
+                current = Entry::Default;
+                default = Some((None, None));
+            },
+            "login" | "password" => {
+                let value = tokens.next().map(|value| value.to_owned());
+                let entry = match current {
+                    Entry::Host => found.as_mut(),
+                    Entry::Default => default.as_mut(),
+                    Entry::Other => None,
+                };
+                if let Some(&mut (ref mut login, ref mut password)) = entry {
+                    if token == "login" {
+                        *login = value;
+                    } else {
+                        *password = value;
+                    }
+                }
+            },
+            _ => (),
+        }
+    }
+
+    match found.or(default) {
+        Some((Some(login), password)) => Some((login, password)),
+        _ => None,
+    }
+}
@@ -0,0 +1,106 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use sniff::{self, ContentKind};
+use source::read_limited;
+
+/// Wait before opening a FIFO again that could not be opened
+const FIFO_RETRY: u64 = 5;
+
+/// Source of a picture piped to stdin, it cannot be loaded again by
+/// name
+pub const PICTURE_SOURCE: &str = "<stdin>";
+
+/// What arrived on stdin so far
+#[derive(Default)]
+pub struct Stdin {
+    /// Paths or URLs, one per line, to load again once stdin is closed
+    pub lines: Vec<String>,
+    /// Raw picture data instead of lines
+    pub picture: Option<Vec<u8>>,
+    pub closed: bool,
+}
+
+/// Reads stdin in the background, as lines or as one picture if it
+/// starts like a picture file
+///
+/// Lines are also put in the queue as they arrive, like those of a
+/// FIFO, a picture as PICTURE_SOURCE.
+///
+/// max_bytes: limits the picture
+pub fn spawn_stdin(max_bytes: u64, queue: Arc<Mutex<VecDeque<String>>>) -> Arc<Mutex<Stdin>> {
+    let state = Arc::new(Mutex::new(Stdin::default()));
+    let reader_state = state.clone();
+    thread::spawn(move|| {
+        let stdin = io::stdin();
+        let mut stdin = stdin.lock();
+        /* Whatever arrived first, lines are not waited for */
+        let is_picture = stdin.fill_buf()
+            .map(|buf| matches!(sniff::sniff(buf), Some(ContentKind::Jpeg) | Some(ContentKind::Image)))
+            .unwrap_or(false);
+        if is_picture {
+            match read_limited(&mut stdin, max_bytes) {
+                Ok(data) => {
+                    reader_state.lock().unwrap().picture = Some(data);
+                    queue.lock().unwrap().push_back(PICTURE_SOURCE.to_owned());
+                },
+                Err(e) => println!("Error reading picture from stdin: {}", e),
+            }
+        } else {
+            for line in stdin.lines() {
+                match line {
+                    Ok(line) => {
+                        let line = line.trim();
+                        /* Don't read stdin recursively */
+                        if !line.is_empty() && line != "-" {
+                            reader_state.lock().unwrap().lines.push(line.to_owned());
+                            queue.lock().unwrap().push_back(line.to_owned());
+                        }
+                    },
+                    Err(e) => {
+                        println!("Error reading stdin: {}", e);
+                        break
+                    }
+                }
+            }
+        }
+        reader_state.lock().unwrap().closed = true;
+    });
+    state
+}
+
+/// Reads lines from a named pipe into a queue, opening it again
+/// whenever a writer closes it
+pub fn spawn_fifo(path: String, queue: Arc<Mutex<VecDeque<String>>>) {
+    thread::spawn(move|| {
+        loop {
+            /* Blocks until there is a writer */
+            let file = match File::open(&path) {
+                Ok(file) => file,
+                Err(e) => {
+                    println!("Cannot open {}: {}", path, e);
+                    thread::sleep(Duration::from_secs(FIFO_RETRY));
+                    continue
+                }
+            };
+            for line in BufReader::new(file).lines() {
+                match line {
+                    Ok(line) => {
+                        let line = line.trim();
+                        if !line.is_empty() {
+                            queue.lock().unwrap().push_back(line.to_owned());
+                        }
+                    },
+                    Err(e) => {
+                        println!("Error reading {}: {}", path, e);
+                        break
+                    }
+                }
+            }
+        }
+    });
+}
@@ -0,0 +1,118 @@
+use config::{parse_seconds, parse_header};
+use http::Auth;
+use source::SourceOptions;
+
+/// Directive prefix for per-entry options, e.g.
+/// `#KENBURNS:duration=8,caption=Sunset,focus=0.3:0.6`
+const DIRECTIVE: &str = "#KENBURNS:";
+
+/// A path or URL with the options given by the lines above it
+#[derive(Debug)]
+pub struct Entry {
+    pub location: String,
+    /// From `#EXTINF`
+    pub title: Option<String>,
+    pub options: SourceOptions,
+}
+
+pub fn is_playlist(filename: &str) -> bool {
+    let lower = filename.to_lowercase();
+    let path = lower.split(['?', '#'])
+        .next()
+        .unwrap_or("");
+    path.ends_with(".m3u") ||
+        path.ends_with(".m3u8") ||
+        path.ends_with(".txt")
+}
+
+/// Parses M3U and plain lists of one path or URL per line
+///
+/// Directives apply to the next entry only.
+pub fn parse(name: &str, text: &str) -> Vec<Entry> {
+    let mut entries = vec![];
+    let mut title = None;
+    let mut options = SourceOptions::default();
+    for line in text.lines() {
+        let line = line.trim();
+        if let Some(directive) = line.strip_prefix(DIRECTIVE) {
+            if let Err(e) = parse_directive(directive, &mut options) {
+                println!("{}: {}", name, e);
+            }
+        } else if line.starts_with("#EXTINF:") {
+            /* #EXTINF:duration,title */
+            title = match line.split_once(',').map(|(_, title)| title.trim()) {
+                Some(title) if !title.is_empty() => Some(title.to_owned()),
+                _ => None,
+            };
+        } else if line.starts_with('#') || line.is_empty() {
+            /* Comment or #EXTM3U */
+        } else {
+            entries.push(Entry {
+                location: line.to_owned(),
+                title: title.take(),
+                options,
+            });
+            options = SourceOptions::default();
+        }
+    }
+    entries
+}
+
+/// `key=value` pairs separated by commas, a caption may contain commas
+/// if it comes last
+fn parse_directive(directive: &str, options: &mut SourceOptions) -> Result<(), String> {
+    let mut pairs: Vec<(String, String)> = vec![];
+    for part in directive.split(',') {
+        let mut kv = part.splitn(2, '=');
+        match (kv.next(), kv.next()) {
+            (Some(key), Some(value)) =>
+                pairs.push((key.trim().to_owned(), value.to_owned())),
+            _ =>
+                match pairs.last_mut() {
+                    Some(&mut (_, ref mut value)) => {
+                        value.push(',');
+                        value.push_str(part);
+                    },
+                    None =>
+                        return Err(format!("Invalid directive: {}", directive)),
+                },
+        }
+    }
+
+    for (key, value) in pairs {
+        match key.as_str() {
+            "duration" =>
+                options.duration = Some(parse_seconds("duration", Some(value.trim()))?),
+            "caption" =>
+                options.caption = Some(value.trim().to_owned()),
+            "follow-html" =>
+                options.html_depth = match value.trim() {
+                    "true" => Some(options.html_depth.unwrap_or(1).max(1)),
+                    "false" => Some(0),
+                    _ => return Err(format!("Invalid follow-html {}, expected true or false", value)),
+                },
+            "header" =>
+                options.request.headers.push((None, parse_header(&value)?)),
+            "basic-auth" =>
+                options.request.auth.push((None, Auth::basic(value.trim()))),
+            "bearer-auth" =>
+                options.request.auth.push((None, Auth::Bearer(value.trim().to_owned()))),
+            "html-depth" =>
+                options.html_depth = Some(value.trim().parse()
+                                          .map_err(|e| format!("Invalid html-depth {}: {}", value, e))?),
+            "focus" => {
+                let mut xy = value.trim().splitn(2, ':')
+                    .map(|n| n.parse::<f32>());
+                match (xy.next(), xy.next()) {
+                    (Some(Ok(x)), Some(Ok(y))) if (0.0..=1.0).contains(&x) && (0.0..=1.0).contains(&y) =>
+                        options.focus = Some((x, y)),
+                    _ =>
+                        return Err(format!("Invalid focus {}, expected X:Y between 0 and 1", value)),
+                }
+            },
+            _ =>
+                return Err(format!("Unknown directive {}", key)),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,220 @@
+use std::collections::VecDeque;
+use std::sync::mpsc::{sync_channel, SyncSender, Receiver};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use futures::{future, Future, Stream};
+use futures::future::Either;
+use futures::sync::mpsc::{unbounded, UnboundedSender};
+use futures::sync::oneshot;
+use futures_cpupool::CpuPool;
+
+use source::{Job, LoadedPicture};
+use dedup::Dedup;
+use favorites::Favorites;
+
+/// A job between submission and delivery
+struct Pending {
+    /// Top-level source the job was found in
+    root: String,
+    source: String,
+    /// Checked between the steps of a job
+    cancel: Arc<AtomicBool>,
+    /// Drops the job right away, even while a download stalls
+    abort: Option<oneshot::Sender<()>>,
+}
+
+impl Pending {
+    fn cancel(&mut self) {
+        println!("Cancelling {}", self.source);
+        self.cancel.store(true, Ordering::Relaxed);
+        if let Some(abort) = self.abort.take() {
+            let _ = abort.send(());
+        }
+    }
+}
+
+/// The top-level source the loader is walking, and the flag that
+/// stops its requests
+struct Listing {
+    root: String,
+    cancel: Arc<AtomicBool>,
+}
+
+/// What Prefetcher::submit() passes to the pipeline
+type Submitted = (Job, Arc<AtomicBool>, oneshot::Receiver<()>);
+
+/// Runs jobs as an async pipeline while delivering their pictures in
+/// the order they were submitted
+///
+/// HTTP is fetched on the reactor thread, files are read and
+/// pictures decoded and scaled on a pool of worker threads.
+pub struct Prefetcher {
+    jobs_tx: UnboundedSender<Submitted>,
+    /// One token per picture that may be in flight or held in memory
+    tokens_rx: Receiver<()>,
+    /// Sources of submitted jobs not yet delivered
+    queue: Arc<Mutex<VecDeque<String>>>,
+    pending: Arc<Mutex<VecDeque<Pending>>>,
+    listing: Arc<Mutex<Listing>>,
+}
+
+impl Prefetcher {
+    /**
+    * workers: number of threads reading and decoding
+    * prefetch: pictures in flight or decoded before the renderer
+    *   takes them
+    * dedup: drops near duplicates before delivering
+    * favorites: drops banned pictures before delivering
+    **/
+    pub fn new(workers: usize, prefetch: usize, tx: SyncSender<LoadedPicture>, display_size: Arc<Mutex<(u32, u32)>>, dedup: Option<Arc<Mutex<Dedup>>>, favorites: Arc<Mutex<Favorites>>) -> Self {
+        let (jobs_tx, jobs_rx) = unbounded::<Submitted>();
+        let (tokens_tx, tokens_rx) = sync_channel(prefetch);
+        let queue = Arc::new(Mutex::new(VecDeque::new()));
+        let pending = Arc::new(Mutex::new(VecDeque::new()));
+        for _ in 0..prefetch {
+            tokens_tx.send(()).unwrap();
+        }
+
+        let pool = CpuPool::new(workers);
+        let delivered_queue = queue.clone();
+        let delivered_pending = pending.clone();
+        thread::spawn(move|| {
+            let pictures = jobs_rx
+                .map(move |(job, cancel, abort): Submitted| {
+                    /* A dropped sender is no reason to stop */
+                    let abort = abort.then(|aborted| match aborted {
+                        Ok(()) => Either::A(future::ok::<(), ()>(())),
+                        Err(_) => Either::B(future::empty()),
+                    });
+                    job.load(&pool, display_size.clone(), cancel)
+                        .select2(abort)
+                        .then(|loaded| match loaded {
+                            Ok(Either::A((picture, _))) => Ok(picture),
+                            _ => Ok(None),
+                        })
+                })
+                .buffered(prefetch);
+            /* Blocks while the renderer doesn't take pictures, which
+             * keeps tokens from being returned */
+            for picture in pictures.wait() {
+                if let Ok(Some(picture)) = picture {
+                    let banned = favorites.lock().unwrap().is_banned(&picture.info);
+                    if banned {
+                        println!("Skipping {}, banned", picture.info.source);
+                    }
+                    let deliver = !banned && dedup.as_ref()
+                        .map(|dedup| dedup.lock().unwrap().check(&picture.info))
+                        .unwrap_or(true);
+                    if deliver {
+                        if let Err(e) = tx.send(picture) {
+                            println!("Error transferring picture to main thread: {}", e);
+                            return
+                        }
+                    }
+                }
+                delivered_queue.lock().unwrap().pop_front();
+                delivered_pending.lock().unwrap().pop_front();
+                let _ = tokens_tx.send(());
+            }
+        });
+
+        Prefetcher {
+            jobs_tx,
+            tokens_rx,
+            queue,
+            pending,
+            listing: Arc::new(Mutex::new(Listing {
+                root: String::new(),
+                cancel: Arc::new(AtomicBool::new(false)),
+            })),
+        }
+    }
+
+    pub fn get_queue(&self) -> Arc<Mutex<VecDeque<String>>> {
+        self.queue.clone()
+    }
+
+    pub fn get_canceller(&self) -> Canceller {
+        Canceller {
+            pending: self.pending.clone(),
+            listing: self.listing.clone(),
+        }
+    }
+
+    /// The loader is now walking root, cancel stops its requests
+    pub fn set_listing(&self, root: &str, cancel: Arc<AtomicBool>) {
+        *self.listing.lock().unwrap() = Listing {
+            root: root.to_owned(),
+            cancel,
+        };
+    }
+
+    /// Blocks until there is room for another picture
+    ///
+    /// root: top-level source the job was found in
+    pub fn submit(&self, job: Job, root: &str) {
+        self.tokens_rx.recv().unwrap();
+        let cancel = Arc::new(AtomicBool::new(false));
+        let (abort_tx, abort_rx) = oneshot::channel();
+        self.queue.lock().unwrap().push_back(job.info.source.clone());
+        self.pending.lock().unwrap().push_back(Pending {
+            root: root.to_owned(),
+            source: job.info.source.clone(),
+            cancel: cancel.clone(),
+            abort: Some(abort_tx),
+        });
+        if self.jobs_tx.unbounded_send((job, cancel, abort_rx)).is_err() {
+            println!("Prefetcher stopped");
+        }
+    }
+}
+
+/// Stops jobs that are no longer wanted, wherever they are in the
+/// pipeline
+#[derive(Clone)]
+pub struct Canceller {
+    pending: Arc<Mutex<VecDeque<Pending>>>,
+    listing: Arc<Mutex<Listing>>,
+}
+
+impl Canceller {
+    /// Everything found in a source that was removed, and the
+    /// loader's requests while it is still walking it
+    pub fn cancel_root(&self, root: &str) {
+        self.cancel(|pending| pending.root == root);
+        let listing = self.listing.lock().unwrap();
+        if listing.root == root {
+            listing.cancel.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Gives up on what holds up the next picture: the first job that
+    /// is still loading, or the loader's request while nothing is
+    /// queued
+    pub fn skip(&self) {
+        let mut pending = self.pending.lock().unwrap();
+        match pending.iter_mut().find(|pending| !pending.cancel.load(Ordering::Relaxed)) {
+            Some(pending) =>
+                pending.cancel(),
+            None => {
+                let listing = self.listing.lock().unwrap();
+                println!("Cancelling requests for {}", listing.root);
+                listing.cancel.store(true, Ordering::Relaxed);
+            },
+        }
+    }
+
+    /// Queued copies of a picture that is shown or banned already
+    pub fn cancel_source(&self, source: &str) {
+        self.cancel(|pending| pending.source == source)
+    }
+
+    fn cancel<F: Fn(&Pending) -> bool>(&self, matches: F) {
+        for pending in self.pending.lock().unwrap().iter_mut() {
+            if matches(pending) {
+                pending.cancel();
+            }
+        }
+    }
+}
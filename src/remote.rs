@@ -0,0 +1,432 @@
+use std::collections::HashMap;
+use std::net::TcpListener;
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use futures::future;
+use futures::{Future, Stream};
+use hyper::{self, Method, StatusCode};
+use hyper::header::{Authorization, Bearer, ContentType};
+use hyper::server::{Http, Request, Response, Service};
+use serde::Serialize;
+use serde_json;
+use tokio_core::net;
+use tokio_core::reactor::Core;
+use url::form_urlencoded;
+
+use config::parse_seconds;
+use render::{spawn_jump, Command, Renderer, ScreenStatus, Timing};
+use source::{LoaderHandle, LoadedPicture};
+use favorites::Favorites;
+
+/// Commands for one screen, or all of them
+pub type RemoteCommand = (Option<usize>, Command);
+
+/// Embedded HTTP server controlling the renderers
+///
+/// * `POST /pause`, `/resume`, `/next`, `/previous`
+/// * `POST /jump?source=...`
+/// * `POST /durations?show=...&transition=...` in seconds
+/// * `POST /screenshot`
+/// * `POST /star`, `POST /ban`
+/// * `GET /favorites`
+/// * `GET /queue`
+/// * `GET /sources`, `POST /sources?add=...`, `DELETE /sources?remove=...`
+/// * `GET /current`
+///
+/// Commands take an optional `screen=N`, and go to all screens without.
+/// Queue and sources are those of the screen's loader, screen 0 by
+/// default.
+#[derive(Clone)]
+pub struct Remote {
+    token: Option<String>,
+    commands: Arc<Mutex<Sender<RemoteCommand>>>,
+    /// Updated by the main loop, one per screen
+    status: Arc<Mutex<Vec<ScreenStatus>>>,
+    /// One per screen, screens sharing the pool have clones
+    loaders: Vec<LoaderHandle>,
+    favorites: Arc<Mutex<Favorites>>,
+}
+
+impl Remote {
+    pub fn new(token: Option<String>, commands: Sender<RemoteCommand>, status: Arc<Mutex<Vec<ScreenStatus>>>, loaders: Vec<LoaderHandle>, favorites: Arc<Mutex<Favorites>>) -> Self {
+        Remote {
+            token,
+            commands: Arc::new(Mutex::new(commands)),
+            status,
+            loaders,
+            favorites,
+        }
+    }
+
+    /// Serves on a listener bound by the caller, so that binding errors
+    /// are reported right away
+    pub fn spawn(self, listener: TcpListener) {
+        thread::spawn(move|| {
+            let mut core = Core::new().expect("reactor");
+            let handle = core.handle();
+            let addr = listener.local_addr().unwrap();
+            let listener = match net::TcpListener::from_listener(listener, &addr, &handle) {
+                Ok(listener) => listener,
+                Err(e) => {
+                    println!("Cannot start remote control on {}: {}", addr, e);
+                    return
+                }
+            };
+            println!("Remote control on http://{}/", addr);
+            let incoming = listener.incoming().map(|(socket, _)| socket);
+            let server = Http::new()
+                .serve_incoming(incoming, move || Ok(self.clone()))
+                .for_each(|connection| {
+                    handle.spawn(connection.map(|_| ()).map_err(|e| println!("Remote control: {}", e)));
+                    Ok(())
+                });
+            if let Err(e) = core.run(server) {
+                println!("Remote control: {}", e);
+            }
+        });
+    }
+
+    fn is_authorized(&self, req: &Request) -> bool {
+        match self.token {
+            None => true,
+            Some(ref token) =>
+                match req.headers().get::<Authorization<Bearer>>() {
+                    Some(Authorization(bearer)) => &bearer.token == token,
+                    None => false,
+                },
+        }
+    }
+
+    fn send(&self, screen: Option<usize>, command: Command) -> Response {
+        match self.commands.lock().unwrap().send((screen, command)) {
+            Ok(()) => Response::new().with_status(StatusCode::NoContent),
+            Err(_) => error(StatusCode::ServiceUnavailable, "Renderer is gone"),
+        }
+    }
+
+    fn route(&self, method: &Method, path: &str, params: &HashMap<String, String>) -> Result<Response, Response> {
+        let screen = match params.get("screen") {
+            None => None,
+            Some(screen) =>
+                match screen.parse::<usize>() {
+                    Ok(screen) if screen < self.loaders.len() =>
+                        Some(screen),
+                    _ =>
+                        return Err(error(StatusCode::BadRequest, "Invalid screen")),
+                },
+        };
+        let loader = &self.loaders[screen.unwrap_or(0)];
+        let param = |name: &str| params.get(name)
+            .ok_or_else(|| error(StatusCode::BadRequest, &format!("Missing parameter {}", name)));
+
+        match (method, path) {
+            (&Method::Post, "/pause") =>
+                Ok(self.send(screen, Command::Pause)),
+            (&Method::Post, "/resume") =>
+                Ok(self.send(screen, Command::Resume)),
+            (&Method::Post, "/next") =>
+                Ok(self.send(screen, Command::Next)),
+            (&Method::Post, "/previous") =>
+                Ok(self.send(screen, Command::Previous)),
+            (&Method::Post, "/jump") => {
+                let source = param("source")?;
+                Ok(self.send(screen, Command::Jump(source.clone())))
+            },
+            (&Method::Post, "/durations") => {
+                let parse = |name| parse_seconds(name, params.get(name).map(|value| value.as_str()))
+                    .map_err(|e| error(StatusCode::BadRequest, &e));
+                let timing = Timing {
+                    show: parse("show")?,
+                    transition: parse("transition")?,
+                };
+                timing.check()
+                    .map_err(|e| error(StatusCode::BadRequest, &e))?;
+                Ok(self.send(screen, Command::SetTiming(timing)))
+            },
+            (&Method::Post, "/screenshot") =>
+                Ok(self.send(screen, Command::Screenshot)),
+            (&Method::Post, "/star") =>
+                Ok(self.send(screen, Command::Star)),
+            (&Method::Post, "/ban") =>
+                Ok(self.send(screen, Command::Ban)),
+            (&Method::Get, "/favorites") =>
+                Ok(json(&self.favorites.lock().unwrap().get_starred())),
+            (&Method::Get, "/queue") => {
+                let queue: Vec<String> = loader.queue.lock().unwrap()
+                    .iter()
+                    .cloned()
+                    .collect();
+                Ok(json(&queue))
+            },
+            (&Method::Get, "/sources") =>
+                Ok(json(&*loader.sources.lock().unwrap())),
+            (&Method::Post, "/sources") => {
+                let source = param("add")?;
+                loader.sources.lock().unwrap().push(source.clone());
+                Ok(Response::new().with_status(StatusCode::NoContent))
+            },
+            (&Method::Delete, "/sources") => {
+                let source = param("remove")?;
+                loader.sources.lock().unwrap().retain(|other| other != source);
+                loader.canceller.cancel_root(source);
+                Ok(Response::new().with_status(StatusCode::NoContent))
+            },
+            (&Method::Get, "/current") =>
+                Ok(json(&*self.status.lock().unwrap())),
+            _ =>
+                Err(error(StatusCode::NotFound, "Not found")),
+        }
+    }
+}
+
+impl Service for Remote {
+    type Request = Request;
+    type Response = Response;
+    type Error = hyper::Error;
+    type Future = Box<dyn Future<Item=Response, Error=hyper::Error>>;
+
+    fn call(&self, req: Request) -> Self::Future {
+        println!("Remote: {} {}", req.method(), req.uri());
+        let res = if self.is_authorized(&req) {
+            let params: HashMap<String, String> = req.query()
+                .map(|query| form_urlencoded::parse(query.as_bytes())
+                     .into_owned()
+                     .collect())
+                .unwrap_or_else(HashMap::new);
+            match self.route(req.method(), req.path(), &params) {
+                Ok(res) => res,
+                Err(res) => res,
+            }
+        } else {
+            error(StatusCode::Unauthorized, "Unauthorized")
+        };
+        Box::new(future::ok(res))
+    }
+}
+
+/// Hands the commands received so far to the renderers of their
+/// screens
+pub fn dispatch(commands: &Receiver<RemoteCommand>, renderers: &mut [Renderer], jumps_tx: &Sender<(Vec<usize>, LoadedPicture)>) {
+    while let Ok((screen, command)) = commands.try_recv() {
+        let screens: Vec<usize> = renderers.iter()
+            .map(|renderer| renderer.screen())
+            .filter(|&i| screen.map(|screen| screen == i).unwrap_or(true))
+            .collect();
+        match command {
+            Command::Jump(source) =>
+                spawn_jump(renderers, screens, source, jumps_tx.clone()),
+            command =>
+                for renderer in renderers.iter_mut()
+                    .filter(|renderer| screens.contains(&renderer.screen())) {
+                        renderer.command(command.clone());
+                    },
+        }
+    }
+}
+
+/// Shares what a renderer shows for `GET /current` once it changed
+pub fn publish_status(status: &Mutex<Vec<ScreenStatus>>, renderer: &mut Renderer) {
+    if renderer.take_changed() {
+        status.lock().unwrap()[renderer.screen()] = renderer.get_status();
+    }
+}
+
+fn json<T: Serialize>(value: &T) -> Response {
+    match serde_json::to_string_pretty(value) {
+        Ok(body) =>
+            Response::new()
+            .with_header(ContentType::json())
+            .with_body(body),
+        Err(e) =>
+            error(StatusCode::InternalServerError, &format!("{}", e)),
+    }
+}
+
+fn error(status: StatusCode, message: &str) -> Response {
+    Response::new()
+        .with_status(status)
+        .with_header(ContentType::plaintext())
+        .with_body(format!("{}\n", message))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::sync::mpsc::channel;
+    use std::time::{Duration, Instant};
+    use config::Config;
+    use testing;
+    use super::*;
+
+    /// A remote control on a free port, and what it sends to the main
+    /// loop
+    fn remote(token: Option<&str>, loader: LoaderHandle, favorites: Arc<Mutex<Favorites>>) -> (SocketAddr, Receiver<RemoteCommand>, Arc<Mutex<Vec<ScreenStatus>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (commands_tx, commands_rx) = channel();
+        let status = Arc::new(Mutex::new(vec![ScreenStatus::default()]));
+        Remote::new(token.map(|token| token.to_owned()), commands_tx, status.clone(), vec![loader], favorites)
+            .spawn(listener);
+        (addr, commands_rx, status)
+    }
+
+    /// Status and body of one request
+    fn call(addr: SocketAddr, method: &str, path: &str, token: Option<&str>) -> (u16, String) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        let auth = token.map(|token| format!("Authorization: Bearer {}\r\n", token))
+            .unwrap_or_default();
+        /* Not chunked, and closed after the response */
+        write!(stream, "{} {} HTTP/1.0\r\nHost: {}\r\n{}Content-Length: 0\r\n\r\n",
+               method, path, addr, auth).unwrap();
+        let mut res = String::new();
+        stream.read_to_string(&mut res).unwrap();
+        let status = res.split_whitespace().nth(1).unwrap().parse().unwrap();
+        let body = res.split_once("\r\n\r\n").map(|(_, body)| body.to_owned()).unwrap_or_default();
+        (status, body)
+    }
+
+    #[test]
+    fn commands() {
+        let (loader, favorites, _dir) = testing::loader(&[]);
+        let (addr, commands_rx, _) = remote(Some("secret"), loader, favorites);
+        let token = Some("secret");
+
+        assert_eq!(call(addr, "POST", "/pause", None).0, 401);
+        assert_eq!(call(addr, "POST", "/pause", Some("wrong")).0, 401);
+        assert!(commands_rx.try_recv().is_err());
+
+        assert_eq!(call(addr, "POST", "/pause", token).0, 204);
+        assert_eq!(call(addr, "POST", "/next?screen=0", token).0, 204);
+        assert_eq!(call(addr, "POST", "/previous", token).0, 204);
+        assert_eq!(call(addr, "POST", "/jump?source=%2Ftmp%2Fa.jpg", token).0, 204);
+        assert_eq!(call(addr, "POST", "/durations?show=3&transition=0.5", token).0, 204);
+        let commands: Vec<String> = commands_rx.try_iter()
+            .map(|command| format!("{:?}", command))
+            .collect();
+        assert_eq!(commands, vec![
+            "(None, Pause)",
+            "(Some(0), Next)",
+            "(None, Previous)",
+            "(None, Jump(\"/tmp/a.jpg\"))",
+            "(None, SetTiming(Timing { show: 3000000, transition: 500000 }))",
+        ]);
+
+        assert_eq!(call(addr, "POST", "/next?screen=1", token).0, 400);
+        assert_eq!(call(addr, "POST", "/jump", token).0, 400);
+        for query in &["show=x&transition=1", "show=3", "show=1&transition=2", "show=-1&transition=0"] {
+            assert_eq!(call(addr, "POST", &format!("/durations?{}", query), token).0, 400, "{}", query);
+        }
+        assert_eq!(call(addr, "GET", "/nothing", token).0, 404);
+        assert!(commands_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn sources_queue_and_current() {
+        let (started_tx, started_rx) = channel();
+        let started_tx = Mutex::new(started_tx);
+        let server = testing::serve(move |req| {
+            if req.path == "/slow" {
+                started_tx.lock().unwrap().send(()).unwrap();
+                /* Longer than the test waits */
+                thread::sleep(Duration::from_secs(30));
+            }
+            (200, testing::jpeg())
+        });
+        let slow = format!("http://{}/slow", server);
+        let picture = format!("http://{}/a.jpg", server);
+        let (loader, favorites, _dir) = testing::loader(&[&slow]);
+        let source_rx = loader.source_rx.clone();
+        let queue = loader.queue.clone();
+        let (addr, _commands_rx, _status) = remote(None, loader, favorites);
+
+        assert_eq!(call(addr, "GET", "/sources", None),
+                   (200, serde_json::to_string_pretty(&[&slow]).unwrap()));
+        started_rx.recv_timeout(Duration::from_secs(10)).unwrap();
+
+        let add = format!("/sources?add={}", form_urlencoded::byte_serialize(picture.as_bytes()).collect::<String>());
+        assert_eq!(call(addr, "POST", &add, None).0, 204);
+        assert_eq!(call(addr, "POST", "/sources", None).0, 400);
+        /* Stuck on the slow source until it is removed */
+        let remove = format!("/sources?remove={}", form_urlencoded::byte_serialize(slow.as_bytes()).collect::<String>());
+        assert_eq!(call(addr, "DELETE", &remove, None).0, 204);
+        assert_eq!(call(addr, "GET", "/sources", None),
+                   (200, serde_json::to_string_pretty(&[&picture]).unwrap()));
+
+        for _ in 0..100 {
+            if !queue.lock().unwrap().is_empty() {
+                break
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        /* The loader goes round its only source while the queue fills */
+        let (code, body) = call(addr, "GET", "/queue", None);
+        assert_eq!(code, 200);
+        let queued: Vec<String> = serde_json::from_str(&body).unwrap();
+        assert!(!queued.is_empty() && queued.iter().all(|source| *source == picture), "{:?}", queued);
+        let loaded = source_rx.lock().unwrap().recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(loaded.info.source, picture);
+    }
+
+    /// Runs the main loop until `GET /current` passes check, returns
+    /// the sources shown
+    fn run_until<F>(addr: SocketAddr, commands_rx: &Receiver<RemoteCommand>, renderers: &mut [Renderer], status: &Mutex<Vec<ScreenStatus>>, check: F) -> Vec<String>
+        where F: Fn(bool, &[String]) -> bool
+    {
+        let (jumps_tx, _jumps_rx) = channel();
+        for _ in 0..500 {
+            dispatch(commands_rx, renderers, &jumps_tx);
+            for renderer in renderers.iter_mut() {
+                renderer.update();
+                renderer.render();
+                publish_status(status, renderer);
+            }
+            let (code, body) = call(addr, "GET", "/current", None);
+            assert_eq!(code, 200);
+            let current: serde_json::Value = serde_json::from_str(&body).unwrap();
+            let paused = current[0]["paused"].as_bool().unwrap();
+            let shown: Vec<String> = current[0]["current"].as_array().unwrap()
+                .iter()
+                .map(|info| info["source"].as_str().unwrap().to_owned())
+                .collect();
+            if check(paused, &shown) {
+                return shown
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        panic!("Stuck at {:?}", status.lock().unwrap());
+    }
+
+    #[test]
+    #[ignore = "needs an offscreen OpenGL context, run with --ignored"]
+    fn pause_next_and_previous() {
+        let server = testing::serve(|_| (200, testing::jpeg()));
+        let first = format!("http://{}/first.jpg", server);
+        let second = format!("http://{}/second.jpg", server);
+        let (loader, favorites, _dir) = testing::loader(&[&first, &second]);
+        let config = Config::from_args(vec!["--duration=5".to_owned(), "--transition=0.1".to_owned()].into_iter()).unwrap();
+        let mut renderers = vec![Renderer::headless(&config, 0, testing::headless(64, 64), &loader, favorites.clone())];
+        let (addr, commands_rx, status) = remote(None, loader, favorites);
+
+        let shown = run_until(addr, &commands_rx, &mut renderers, &status, |_, shown| !shown.is_empty());
+        assert_eq!(shown, vec![first.clone()]);
+
+        assert_eq!(call(addr, "POST", "/pause", None).0, 204);
+        run_until(addr, &commands_rx, &mut renderers, &status, |paused, _| paused);
+        assert_eq!(call(addr, "POST", "/resume", None).0, 204);
+        run_until(addr, &commands_rx, &mut renderers, &status, |paused, _| !paused);
+
+        assert_eq!(call(addr, "POST", "/next", None).0, 204);
+        let shown = run_until(addr, &commands_rx, &mut renderers, &status, |_, shown| shown != [first.as_str()]);
+        assert_eq!(shown, vec![second.clone()]);
+
+        /* Going back waits for the transition to end */
+        let transitioned = Instant::now() + Duration::from_millis(300);
+        run_until(addr, &commands_rx, &mut renderers, &status, |_, _| Instant::now() > transitioned);
+        assert_eq!(call(addr, "POST", "/previous", None).0, 204);
+        let shown = run_until(addr, &commands_rx, &mut renderers, &status, |_, shown| shown != [second.as_str()]);
+        assert_eq!(shown, vec![first]);
+    }
+}
@@ -1,32 +1,149 @@
 use glium::{Display, Surface};
-use glium::glutin::{WindowBuilder, ContextBuilder, EventsLoop, Event, WindowEvent, VirtualKeyCode, ElementState};
-use glium::texture::{SrgbTexture2d, RawImage2d};
-use glium::{Program, DrawParameters, Depth, Blend, Frame};
+#[cfg(test)]
+use glium::HeadlessRenderer;
+use glium::backend::{Context, Facade};
+use glium::glutin::{EventsLoop, WindowEvent, WindowId, VirtualKeyCode, ElementState};
+use glium::uniforms::{SamplerWrapFunction, MinifySamplerFilter, MagnifySamplerFilter};
+use glium::{Program, DrawParameters, Depth, Blend, Frame, Rect};
 use glium::draw_parameters::DepthTest;
-use glium::vertex::VertexBuffer;
+use glium::texture::{RawImage2d, Texture2d, UncompressedFloatFormat, MipmapsOption};
 use glium::index::{NoIndices, PrimitiveType};
-use std::ops::Not;
-use std::sync::mpsc::Receiver;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::mem::replace;
+use std::ops::{Deref, Not};
+use std::rc::Rc;
+use std::sync::mpsc::{Sender, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use time;
 
 use util::*;
+use config::{Config, Filter, CaptionField, Limits, Anchor};
+use tiles::{Tile, TiledUpload};
+use text::{TextRenderer, TextStyle};
+use source::{Job, LoaderHandle, LoadedPicture, PictureInfo};
+use prefetch::Canceller;
+use widgets::Widget;
+use window::WindowOptions;
+use screenshot;
+use pipe;
+use history::{self, History};
+use favorites::Favorites;
+use http::RequestOptions;
 
-const SHOW_DURATION: u64 = 3_000_000;
-const TRANSITION_DURATION: u64 = 300_000;
+/// Zoom at the end of the show duration
+pub const MAX_ZOOM: f32 = 1.1;
+/// Time per frame to spend on uploading tiles, in microseconds
+const UPLOAD_BUDGET: u64 = 4_000;
+/// Pictures to keep uploaded ahead of the next slide
+const READY_PICTURES: usize = 2;
+/// Slides to keep for going back
+const HISTORY_SLIDES: usize = 5;
+
+/// Show and transition durations, in microseconds
+#[derive(Clone, Copy, Debug)]
+pub struct Timing {
+    pub show: u64,
+    pub transition: u64
+}
+
+impl Timing {
+    /// Slides must be shown for longer than they fade
+    pub fn check(&self) -> Result<(), String> {
+        if self.show == 0 {
+            Err("Show duration must be positive".to_owned())
+        } else if self.show <= self.transition {
+            Err("Show duration must be longer than the transition".to_owned())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Windows to close after an event
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Close {
+    Nothing,
+    Window,
+    All,
+}
+
+/// Remote or keyboard control
+#[derive(Clone, Debug)]
+pub enum Command {
+    Pause,
+    Resume,
+    TogglePause,
+    Next,
+    Previous,
+    /// Show a path or URL next
+    Jump(String),
+    SetTiming(Timing),
+    /// Save the next frame as PNG
+    Screenshot,
+    /// Toggle the star of the current pictures
+    Star,
+    /// Never show the current pictures again
+    Ban
+}
+
+/// What a screen currently shows
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ScreenStatus {
+    pub paused: bool,
+    pub current: Vec<PictureInfo>
+}
 
 struct Picture {
-    texture: SrgbTexture2d
+    width: u32,
+    height: u32,
+    tiles: Vec<Tile>,
+    info: PictureInfo
 }
 
 impl Picture {
-    pub fn new(texture: SrgbTexture2d) -> Self {
+    pub fn new(upload: TiledUpload, info: PictureInfo) -> Self {
+        let (width, height) = upload.dimensions();
         Picture {
-            texture: texture
+            width,
+            height,
+            tiles: upload.into_tiles(),
+            info
+        }
+    }
+
+    /// One line per field that is known, or the playlist entry's
+    /// caption
+    pub fn get_caption(&self, fields: &[CaptionField]) -> Vec<String> {
+        if let Some(ref caption) = self.info.options.caption {
+            return caption.lines()
+                .map(|line| line.to_owned())
+                .collect()
         }
+        fields.iter()
+            .filter_map(|field| match *field {
+                CaptionField::Filename =>
+                    self.info.source
+                    .rsplit('/')
+                    .next()
+                    .map(|filename| filename.to_owned()),
+                CaptionField::Title =>
+                    self.info.title.clone(),
+                CaptionField::Author =>
+                    self.info.author.clone(),
+                CaptionField::Date =>
+                    self.info.exif.date.clone(),
+            })
+            .collect()
     }
 
     pub fn get_aspect_ratio(&self) -> f32 {
-        self.texture.get_width() as f32 /
-            self.texture.get_height().unwrap() as f32
+        self.width as f32 / self.height as f32
+    }
+
+    pub fn is_portrait(&self) -> bool {
+        self.get_aspect_ratio() < 1.0
     }
 }
 
@@ -49,213 +166,737 @@ impl Not for ZoomDirection {
 
 struct PictureState {
     start: u64,
-    zoom_direction: ZoomDirection
+    zoom_direction: ZoomDirection,
+    timing: Timing
 }
 
 impl PictureState {
-    pub fn new(zoom_direction: ZoomDirection) -> Self {
+    pub fn new(zoom_direction: ZoomDirection, start: u64, timing: Timing) -> Self {
         PictureState {
-            start: get_us(),
-            zoom_direction: zoom_direction
+            start,
+            zoom_direction,
+            timing
         }
     }
 
-    pub fn has_transitioned(&self) -> bool {
-        (get_us() - self.start) > TRANSITION_DURATION
+    pub fn has_transitioned(&self, now: u64) -> bool {
+        (now - self.start) > self.timing.transition
     }
 
-    pub fn get_overflowing_t(&self) -> f32 {
-        let now = get_us();
-        (now - self.start) as f32 / SHOW_DURATION as f32
+    pub fn get_overflowing_t(&self, now: u64) -> f32 {
+        (now - self.start) as f32 / self.timing.show as f32
     }
 
-    pub fn get_zoom(&self) -> f32 {
+    pub fn get_zoom(&self, now: u64) -> f32 {
         let time_zoom = match self.zoom_direction {
             ZoomDirection::In =>
                 /* Linear zooming in */
-                self.get_overflowing_t(),
+                self.get_overflowing_t(now),
             ZoomDirection::Out =>
                 /* Slowing zoom out
                 * that stops before showing black borders
                 */
-                (1.0 - self.get_overflowing_t())
+                (1.0 - self.get_overflowing_t(now))
                 .max(0.0)
                 .powf(2.0)
         };
-        1.0 + 0.1 * time_zoom
+        1.0 + (MAX_ZOOM - 1.0) * time_zoom
     }
 
-    pub fn get_alpha(&self) -> f32 {
-        let age = (get_us() - self.start) as f32;
-        (age / TRANSITION_DURATION as f32).min(1.0)
+    pub fn get_alpha(&self, now: u64) -> f32 {
+        let age = (now - self.start) as f32;
+        (age / self.timing.transition as f32).min(1.0)
     }
 }
 
-#[derive(Copy, Clone)]
-struct Vertex {
-    position: [f32; 3],
-    tex_coords: [f32; 2],
+/// What is on screen at once: usually one picture, or two portrait
+/// pictures side by side on a landscape display.
+struct Slide {
+    pictures: Vec<(Picture, PictureState)>,
+    /// Unix time when it appeared, for the history
+    shown_at: i64,
 }
-implement_vertex!(Vertex, position, tex_coords);
 
+impl Slide {
+    pub fn new(pictures: Vec<Picture>, zoom_direction: ZoomDirection, start: u64, timing: Timing) -> Self {
+        let mut zoom_direction = zoom_direction;
+        let pictures = pictures.into_iter()
+            .map(|pic| {
+                let state = PictureState::new(zoom_direction, start, timing);
+                /* Give each picture of a diptych its own motion */
+                zoom_direction = !zoom_direction;
+                (pic, state)
+            })
+            .collect();
+        Slide {
+            pictures,
+            shown_at: time::get_time().sec,
+        }
+    }
 
-pub struct Renderer<'a> {
-    source_rx: Receiver<RawImage2d<'a, u8>>,
-    display: Display,
-    events_loop: EventsLoop,
-    program: Program,
-    current: Option<(Picture, PictureState)>,
-    next: Option<(Picture, PictureState)>
-}
+    pub fn start(&self) -> u64 {
+        self.pictures[0].1.start
+    }
 
-impl<'a> Renderer<'a> {
-    pub fn new(source_rx: Receiver<RawImage2d<'a, u8>>) -> Renderer<'a> {
-        let window = WindowBuilder::new()
-            .with_title("Rust<KenBurns>");
+    pub fn timing(&self) -> Timing {
+        self.pictures[0].1.timing
+    }
 
+    pub fn zoom_direction(&self) -> ZoomDirection {
+        self.pictures[0].1.zoom_direction
+    }
 
-        let context = ContextBuilder::new()
-            .with_depth_buffer(24)
-            .with_vsync(true);
-        let events_loop = EventsLoop::new();
-        let display = Display::new(window, context, &events_loop).unwrap();
-        
-        let vertex_shader_src = r#"
-            #version 140
+    pub fn has_transitioned(&self, now: u64) -> bool {
+        self.pictures[0].1.has_transitioned(now)
+    }
 
-            in vec3 position;
-            in vec2 tex_coords;
+    /// When the next slide should start fading in
+    pub fn is_ending(&self, now: u64) -> bool {
+        let timing = self.timing();
+        now - self.start() >= timing.show.saturating_sub(timing.transition)
+    }
 
-            out vec2 v_tex_coords;
+    pub fn into_pictures(self) -> Vec<Picture> {
+        self.pictures.into_iter()
+            .map(|(pic, _)| pic)
+            .collect()
+    }
+}
 
-            uniform mat4 matrix;
 
-            void main() {
-                v_tex_coords = tex_coords;
-                gl_Position = matrix * vec4(position, 1.0);
-            }
-        "#;
+/// What a renderer draws into
+enum Output {
+    Window(Display),
+    /// Offscreen, for tests
+    #[cfg(test)]
+    Headless(HeadlessRenderer),
+}
 
-        let fragment_shader_src = r#"
-            #version 140
+impl Output {
+    fn draw(&self) -> Frame {
+        match *self {
+            Output::Window(ref display) => display.draw(),
+            #[cfg(test)]
+            Output::Headless(ref display) => display.draw(),
+        }
+    }
+}
 
-            in vec2 v_tex_coords;
-            uniform float alpha;
+impl Facade for Output {
+    fn get_context(&self) -> &Rc<Context> {
+        match *self {
+            Output::Window(ref display) => display.get_context(),
+            #[cfg(test)]
+            Output::Headless(ref display) => display.get_context(),
+        }
+    }
+}
 
-            uniform sampler2D tex;
+impl Deref for Output {
+    type Target = Context;
 
-            out vec4 frag_color;
+    fn deref(&self) -> &Context {
+        self.get_context()
+    }
+}
 
-            void main() {
-                frag_color = texture(tex, v_tex_coords);
-                frag_color.a = alpha;
-            }
-        "#;
+/// One slideshow window
+pub struct Renderer {
+    /// May be shared with other screens
+    source_rx: Arc<Mutex<Receiver<LoadedPicture>>>,
+    display: Output,
+    window_options: WindowOptions,
+    fullscreen: bool,
+    vsync: bool,
+    program: Program,
+    text: RefCell<TextRenderer>,
+    sampling: Sampling,
+    caption: Vec<CaptionField>,
+    caption_style: TextStyle,
+    caption_fade: bool,
+    widgets: Vec<Widget>,
+    /// Index in Config::screens
+    screen: usize,
+    screenshot_dir: String,
+    screenshot_source: bool,
+    /// Read back the frame after rendering
+    take_screenshot: bool,
+    /// Records slides when they are replaced
+    play_history: Option<Arc<Mutex<History>>>,
+    favorites: Arc<Mutex<Favorites>>,
+    /// For pictures loaded outside of the loader
+    limits: Limits,
+    request: RequestOptions,
+    /// Drops pictures from the loader's queue that are shown or
+    /// banned already
+    canceller: Canceller,
+    /// Shared with the loader for downscaling, the largest of all
+    /// screens it serves
+    display_size: Arc<Mutex<(u32, u32)>>,
+    /// Sizes of all screens the loader serves, to recompute
+    /// display_size from
+    screen_sizes: Arc<Mutex<HashMap<usize, (u32, u32)>>>,
+    /// Pictures requested by Command::Jump, see spawn_jump()
+    jumps: VecDeque<LoadedPicture>,
+    /// Jumped-to image whose tiles are being uploaded, before those
+    /// from the loader
+    jump_upload: Option<(TiledUpload, PictureInfo)>,
+    clock: Clock,
+    timing: Timing,
+    /// Start the next slide without waiting
+    skip: bool,
+    /// Status needs to be published
+    changed: bool,
+    current: Option<Slide>,
+    next: Option<Slide>,
+    /// Previously shown, most recent last
+    history: VecDeque<Vec<Picture>>,
+    /// Image whose tiles are being uploaded
+    upload: Option<(TiledUpload, PictureInfo)>,
+    /// Uploaded and waiting to be shown, looked ahead for pairing
+    ready: VecDeque<Picture>
+}
+
+impl Renderer {
+    /**
+    * vsync: only one window should wait for it, or each frame waits
+    *   for all of them
+    **/
+    pub fn new(config: &Config, screen: usize, vsync: bool, events_loop: &EventsLoop, loader: &LoaderHandle, play_history: Option<Arc<Mutex<History>>>, favorites: Arc<Mutex<Favorites>>) -> Renderer {
+        let window_options = &config.screens[screen].window;
+        let window = window_options.window_builder(window_options.fullscreen, events_loop);
+        let context = window_options.context_builder(vsync);
+        let display = Display::new(window, context, events_loop).unwrap();
+        window_options.apply(&display, window_options.fullscreen);
+        Renderer::with_output(config, screen, vsync, Output::Window(display), loader, play_history, favorites)
+    }
+
+    /// Draws offscreen and records no history
+    #[cfg(test)]
+    pub fn headless(config: &Config, screen: usize, display: HeadlessRenderer, loader: &LoaderHandle, favorites: Arc<Mutex<Favorites>>) -> Renderer {
+        Renderer::with_output(config, screen, false, Output::Headless(display), loader, None, favorites)
+    }
 
-        let program = Program::from_source(&display, vertex_shader_src, fragment_shader_src,
-                                           None).unwrap();
-        Renderer {
-            source_rx,
+    fn with_output(config: &Config, screen: usize, vsync: bool, display: Output, loader: &LoaderHandle, play_history: Option<Arc<Mutex<History>>>, favorites: Arc<Mutex<Favorites>>) -> Renderer {
+        let window_options = config.screens[screen].window.clone();
+        let fullscreen = window_options.fullscreen;
+        let program = picture_program(&display);
+        let text = RefCell::new(TextRenderer::new(&display));
+        let mut renderer = Renderer {
+            source_rx: loader.source_rx.clone(),
             display,
-            events_loop,
+            window_options,
+            fullscreen,
+            vsync,
             program,
+            text,
+            sampling: Sampling {
+                filter: config.filter,
+                anisotropy: config.anisotropy,
+            },
+            caption: config.caption.clone(),
+            caption_style: config.caption_style.clone(),
+            caption_fade: config.caption_fade,
+            widgets: config.widgets.clone(),
+            screen,
+            screenshot_dir: config.screenshot_dir.clone(),
+            screenshot_source: config.screenshot_source,
+            take_screenshot: false,
+            play_history,
+            favorites,
+            limits: config.limits,
+            request: config.request.clone(),
+            canceller: loader.canceller.clone(),
+            display_size: loader.display_size.clone(),
+            screen_sizes: loader.screen_sizes.clone(),
+            jumps: VecDeque::new(),
+            jump_upload: None,
+            clock: Clock::new(),
+            timing: config.timing,
+            skip: false,
+            changed: true,
             current: None,
-            next: None
+            next: None,
+            history: VecDeque::new(),
+            upload: None,
+            ready: VecDeque::new()
+        };
+        renderer.update_display_size();
+        renderer
+    }
+
+    /// None while drawing offscreen
+    pub fn window_id(&self) -> Option<WindowId> {
+        match self.display {
+            Output::Window(ref display) => Some(display.gl_window().id()),
+            #[cfg(test)]
+            Output::Headless(_) => None,
+        }
+    }
+
+    /// Screen index in the config
+    pub fn screen(&self) -> usize {
+        self.screen
+    }
+
+    /// Updates the size the loader scales pictures for when the
+    /// window was resized
+    fn update_display_size(&mut self) {
+        let size = self.display.get_framebuffer_dimensions();
+        let mut screen_sizes = self.screen_sizes.lock().unwrap();
+        if screen_sizes.get(&self.screen) != Some(&size) {
+            screen_sizes.insert(self.screen, size);
+            *self.display_size.lock().unwrap() = largest_size(&screen_sizes);
         }
     }
 
-    fn load_next_pic(&mut self) -> Option<Picture> {
-        let t1 = get_us();
-        let image = match self.source_rx.try_recv() {
-            Err(_) => return None,
-            Ok(image) => image
+    /// Shows a picture loaded by spawn_jump() next
+    pub fn jump(&mut self, loaded: LoadedPicture) {
+        self.jumps.push_back(loaded);
+    }
+
+    /// Uploads a jumped-to picture within the frame's budget and shows
+    /// it next
+    fn upload_jump(&mut self) {
+        if self.jump_upload.is_none() {
+            let loaded = match self.jumps.pop_front() {
+                Some(loaded) => loaded,
+                None => return,
+            };
+            let upload = TiledUpload::new(loaded.image, self.sampling.filter.needs_mipmaps());
+            self.jump_upload = Some((upload, loaded.info));
+        }
+        let done = {
+            let &mut (ref mut upload, _) = self.jump_upload.as_mut().unwrap();
+            upload.step(&self.display, get_us() + UPLOAD_BUDGET);
+            upload.is_done()
         };
-        let t2 = get_us();
-        let texture = SrgbTexture2d::new(&self.display, image).unwrap();
-        let t3 = get_us();
-        let pic = Picture::new(texture);
-        let t4 = get_us();
-        println!("Converted pic in {} + {} + {} us", t2 - t1, t3 - t2, t4 - t3);
-        Some(pic)
-    }
-
-    pub fn update(&mut self) -> bool {
-        let mut running = true;
-        // events
-        self.events_loop.poll_events(|ev| {
-            match ev {
-                Event::WindowEvent { event, window_id: _ } =>
-                    match event {
-                        WindowEvent::KeyboardInput { input, device_id: _ }
-                        if input.state == ElementState::Released
-                            && input.virtual_keycode == Some(VirtualKeyCode::Escape) =>
-                            running = false,
-                        WindowEvent::Closed =>
-                            running = false,
-                        _ => (),
-                    },
-                _ => (),
+        if done {
+            let (upload, info) = self.jump_upload.take().unwrap();
+            self.ready.push_front(Picture::new(upload, info));
+            self.skip = true;
+        }
+    }
+
+    /// Continues uploading tiles within the frame's budget
+    fn upload_pictures(&mut self) {
+        /* A jump uses up the budget */
+        if self.jump_upload.is_some() {
+            return
+        }
+        let deadline = get_us() + UPLOAD_BUDGET;
+        while self.ready.len() < READY_PICTURES && get_us() < deadline {
+            if self.upload.is_none() {
+                let next_image = self.source_rx.lock().unwrap().try_recv();
+                match next_image {
+                    Err(_) => return,
+                    Ok(loaded) => {
+                        let mipmaps = self.sampling.filter.needs_mipmaps();
+                        let upload = TiledUpload::new(loaded.image, mipmaps);
+                        self.upload = Some((upload, loaded.info));
+                    }
+                }
             }
-        });
+
+            let done = {
+                let &mut (ref mut upload, _) = self.upload.as_mut().unwrap();
+                upload.step(&self.display, deadline);
+                upload.is_done()
+            };
+            if done {
+                let (upload, info) = self.upload.take().unwrap();
+                let pic = Picture::new(upload, info);
+                println!("Uploaded {} tiles of {}x{} pic from {} (originally {}x{}, fetched in {} us, decoded in {} us)",
+                         pic.tiles.len(), pic.width, pic.height, pic.info.source,
+                         pic.info.original_size.0, pic.info.original_size.1,
+                         pic.info.fetch_time, pic.info.decode_time);
+                self.ready.push_back(pic);
+            }
+        }
+    }
+
+    /// Pairs two portrait pictures in a row if the display is landscape
+    fn load_next_slide(&mut self) -> Option<Vec<Picture>> {
+        let (target_width, target_height) = self.display.get_framebuffer_dimensions();
+        let target_aspect_ratio = target_width as f32 / target_height as f32;
+        let first_is_portrait = match self.ready.front() {
+            None => return None,
+            Some(pic) => pic.is_portrait()
+        };
+        if target_aspect_ratio > 1.0 && first_is_portrait {
+            match self.ready.get(1).map(|pic| pic.is_portrait()) {
+                Some(true) => {
+                    let pic = self.ready.pop_front().unwrap();
+                    let other = self.ready.pop_front().unwrap();
+                    return Some(vec![pic, other])
+                },
+                Some(false) => (),
+                /* Wait for the picture that is still uploading */
+                None if self.upload.is_some() =>
+                    return None,
+                None => ()
+            }
+        }
+        self.ready.pop_front().map(|pic| vec![pic])
+    }
+
+    fn toggle_fullscreen(&mut self, events_loop: &EventsLoop) {
+        self.fullscreen = !self.fullscreen;
+        if let Err(e) = self.rebuild(events_loop) {
+            println!("Cannot toggle fullscreen: {}", e);
+        }
+    }
+
+    /// Takes over waiting for vsync from a window that was closed
+    pub fn enable_vsync(&mut self, events_loop: &EventsLoop) {
+        if self.vsync {
+            return
+        }
+        self.vsync = true;
+        if let Err(e) = self.rebuild(events_loop) {
+            println!("Cannot enable vsync: {}", e);
+        }
+    }
+
+    /// Recreates the window with the current settings
+    fn rebuild(&mut self, events_loop: &EventsLoop) -> Result<(), String> {
+        match self.display {
+            Output::Window(ref display) => {
+                let window = self.window_options.window_builder(self.fullscreen, events_loop);
+                let context = self.window_options.context_builder(self.vsync);
+                display.rebuild(window, context, events_loop)
+                    .map_err(|e| format!("{}", e))?;
+                self.window_options.apply(display, self.fullscreen);
+                Ok(())
+            },
+            #[cfg(test)]
+            Output::Headless(_) =>
+                Err("No window".to_owned()),
+        }
+    }
+
+    pub fn command(&mut self, command: Command) {
+        println!("Command: {:?}", command);
+        match command {
+            Command::Pause =>
+                self.clock.pause(),
+            Command::Resume =>
+                self.clock.resume(),
+            Command::TogglePause =>
+                if self.clock.is_paused() {
+                    self.clock.resume()
+                } else {
+                    self.clock.pause()
+                },
+            Command::Next => {
+                /* Don't wait for a picture that is stuck loading */
+                if self.ready.is_empty() && self.upload.is_none() {
+                    self.canceller.skip();
+                }
+                self.skip = true
+            },
+            Command::Previous =>
+                self.go_back(),
+            /* Loaded once for all screens by spawn_jump() */
+            Command::Jump(_) =>
+                (),
+            Command::SetTiming(timing) =>
+                self.timing = timing,
+            Command::Screenshot =>
+                self.take_screenshot = true,
+            Command::Star => {
+                let mut favorites = self.favorites.lock().unwrap();
+                for info in self.get_status().current {
+                    if favorites.toggle_star(&info.source) {
+                        println!("Starred {}", info.source);
+                    } else {
+                        println!("Unstarred {}", info.source);
+                    }
+                }
+            },
+            Command::Ban =>
+                self.ban_current(),
+        }
+        self.changed = true;
+    }
+
+    /// Bans the current pictures, drops them from what is uploaded
+    /// and moves on
+    fn ban_current(&mut self) {
+        let current = self.get_status().current;
+        let mut favorites = self.favorites.lock().unwrap();
+        for info in &current {
+            favorites.ban(info);
+            self.canceller.cancel_source(&info.source);
+        }
+        self.ready.retain(|pic| !favorites.is_banned(&pic.info));
+        for pictures in self.history.iter_mut() {
+            pictures.retain(|pic| !favorites.is_banned(&pic.info));
+        }
+        self.history.retain(|pictures| !pictures.is_empty());
+        self.skip = true;
+    }
+
+    /// Fades in the previous slide, the current one comes again after
+    /// it
+    fn go_back(&mut self) {
+        if self.next.is_some() {
+            /* Still in transition */
+            return
+        }
+        let pictures = match self.history.pop_back() {
+            Some(pictures) => pictures,
+            None => return,
+        };
+        if let Some(current) = self.current.take() {
+            for pic in current.into_pictures().into_iter().rev() {
+                self.ready.push_front(pic);
+            }
+        }
+        let now = self.clock.now();
+        self.next = Some(Slide::new(pictures, ZoomDirection::In, now, self.timing));
+    }
+
+    pub fn get_status(&self) -> ScreenStatus {
+        let slide = self.next.as_ref().or(self.current.as_ref());
+        ScreenStatus {
+            paused: self.clock.is_paused(),
+            current: slide
+                .map(|slide| slide.pictures.iter()
+                     .map(|(pic, _)| pic.info.clone())
+                     .collect())
+                .unwrap_or_default()
+        }
+    }
+
+    /// Whether get_status() has changed since the last call
+    pub fn take_changed(&mut self) -> bool {
+        replace(&mut self.changed, false)
+    }
+
+    /// Returns false to quit
+    pub fn handle_event(&mut self, event: WindowEvent, events_loop: &EventsLoop) -> Close {
+        match event {
+            WindowEvent::KeyboardInput { input, device_id: _ }
+            if input.state == ElementState::Released =>
+                match input.virtual_keycode {
+                    Some(VirtualKeyCode::Escape) =>
+                        return Close::All,
+                    Some(VirtualKeyCode::F) | Some(VirtualKeyCode::F11) =>
+                        self.toggle_fullscreen(events_loop),
+                    Some(VirtualKeyCode::Space) =>
+                        self.command(Command::TogglePause),
+                    Some(VirtualKeyCode::Right) =>
+                        self.command(Command::Next),
+                    Some(VirtualKeyCode::Left) =>
+                        self.command(Command::Previous),
+                    Some(VirtualKeyCode::S) | Some(VirtualKeyCode::Snapshot) =>
+                        self.command(Command::Screenshot),
+                    Some(VirtualKeyCode::L) =>
+                        self.command(Command::Star),
+                    Some(VirtualKeyCode::Delete) =>
+                        self.command(Command::Ban),
+                    _ => (),
+                },
+            WindowEvent::Closed =>
+                return Close::Window,
+            _ => (),
+        }
+        Close::Nothing
+    }
+
+    pub fn update(&mut self) {
+        self.update_display_size();
+        self.upload_jump();
+        self.upload_pictures();
 
         // elapse/rotate
         let mut rotate_current = false;
         let mut create_next = false;
-        let now = get_us();
+        let now = self.clock.now();
         match (&self.current, &self.next) {
-            (_, &Some((_, ref next_state)))
-                if next_state.has_transitioned() =>
+            (_, Some(next))
+                if next.has_transitioned(now) =>
                     rotate_current = true,
             (&None, &None) =>
                 create_next = true,
-            (&Some((_, ref current_state)), &None)
-                if now - current_state.start >= SHOW_DURATION - TRANSITION_DURATION =>
+            (Some(current), &None)
+                if self.skip || current.is_ending(now) =>
                     create_next = true,
             (_, _) => ()
         }
         if rotate_current {
-            self.current = self.next.take();
+            let next = self.next.take();
+            if let Some(previous) = replace(&mut self.current, next) {
+                self.record(&previous, now);
+                self.history.push_back(previous.into_pictures());
+                while self.history.len() > HISTORY_SLIDES {
+                    self.history.pop_front();
+                }
+            }
         } else if create_next {
-            self.load_next_pic().map(|pic|{
+            let default_timing = self.timing;
+            if let Some(pictures) = self.load_next_slide() {
+                /* Playlist entries may set their own duration */
+                let timing = pictures.iter()
+                    .filter_map(|pic| pic.info.options.duration)
+                    .max()
+                    .map(|show| Timing { show, .. default_timing })
+                    .filter(|timing| timing.check().is_ok())
+                    .unwrap_or(default_timing);
                 let current_direction = self.current
                     .as_ref()
-                    .map(|&(_, ref current_state)| current_state.zoom_direction);
-                let pic_state = PictureState::new(!current_direction.unwrap_or(ZoomDirection::Out));
-                self.next = Some((pic, pic_state));
-            });
+                    .map(|current| current.zoom_direction());
+                let slide = Slide::new(pictures, !current_direction.unwrap_or(ZoomDirection::Out), now, timing);
+                self.next = Some(slide);
+                self.skip = false;
+                self.changed = true;
+            }
         }
+    }
 
-        running
+    fn record(&self, slide: &Slide, now: u64) {
+        let play_history = match self.play_history {
+            Some(ref play_history) => play_history,
+            None => return,
+        };
+        let duration = now - slide.start();
+        let shown_at = slide.shown_at;
+        let mut play_history = play_history.lock().unwrap();
+        for (pic, _) in &slide.pictures {
+            play_history.record(history::Entry {
+                source: pic.info.source.clone(),
+                screen: self.screen,
+                shown_at,
+                duration,
+                title: pic.info.title.clone(),
+            });
+        }
     }
 
-    pub fn render(&self) {
+    pub fn render(&mut self) {
         let mut target = self.display.draw();
-        let (target_width, target_height) = target.get_dimensions();
-        let target_aspect_ratio = target_width as f32 / target_height as f32;
+        let now = self.clock.now();
 
         target.clear_color_and_depth((0.0, 0.0, 0.0, 1.0), 1.0);
 
         match self.current {
             None => (),
-            Some((ref current_pic, ref current_state)) =>
-                self.render_picture(&mut target, current_pic, current_state, target_aspect_ratio)
+            Some(ref current) =>
+                self.render_slide(&mut target, current, now)
         }
         match self.next {
             None => (),
-            Some((ref next_pic, ref next_state)) =>
-                self.render_picture(&mut target, next_pic, next_state, target_aspect_ratio)
+            Some(ref next) =>
+                self.render_slide(&mut target, next, now)
         }
+        self.render_widgets(&mut target);
 
+        if replace(&mut self.take_screenshot, false) {
+            self.screenshot(&target);
+        }
         target.finish().unwrap();
     }
 
-    fn render_picture(&self, target: &mut Frame, pic: &Picture, state: &PictureState, target_aspect_ratio: f32) {
-        let shape = VertexBuffer::new(&self.display, &[
-            Vertex { position: [-1.0,  1.0, 0.0], tex_coords: [0.0, 1.0] },
-            Vertex { position: [ 1.0,  1.0, 0.0], tex_coords: [1.0, 1.0] },
-            Vertex { position: [-1.0, -1.0, 0.0], tex_coords: [0.0, 0.0] },
-            Vertex { position: [ 1.0, -1.0, 0.0], tex_coords: [1.0, 0.0] },
-        ]).unwrap();
+    /// Copies the frame before it is swapped, the front buffer may
+    /// not hold it yet
+    fn screenshot(&self, target: &Frame) {
+        let (width, height) = target.get_dimensions();
+        let texture = match Texture2d::empty_with_format(&self.display, UncompressedFloatFormat::U8U8U8U8,
+                                                         MipmapsOption::NoMipmap, width, height) {
+            Ok(texture) => texture,
+            Err(e) => {
+                println!("Error taking screenshot: {}", e);
+                return
+            }
+        };
+        target.fill(&texture.as_surface(), MagnifySamplerFilter::Nearest);
+        let frame: RawImage2d<'static, u8> = texture.read();
+        let sources = if self.screenshot_source {
+            self.get_status().current
+        } else {
+            vec![]
+        };
+        screenshot::save(frame, self.screenshot_dir.clone(), self.screen, sources, self.limits);
+    }
+
+    fn render_widgets(&self, target: &mut Frame) {
+        let (target_width, target_height) = target.get_dimensions();
+        let mut text = self.text.borrow_mut();
+        /* Each widget gets a slot just tall enough for its text and
+         * margins */
+        let slots: Vec<(&Widget, Vec<String>, u32)> = self.widgets.iter()
+            .map(|widget| {
+                let lines = widget.get_lines();
+                let height = text.measure(&lines, &widget.style);
+                let slot_height = if height > 0.0 {
+                    (height + 2.0 * widget.style.margin).ceil() as u32
+                } else {
+                    0
+                };
+                (widget, lines, slot_height)
+            })
+            .collect();
+
+        /* Widgets sharing an anchor are stacked in the order they were
+         * given */
+        let mut anchors: Vec<Anchor> = vec![];
+        for &(widget, _, _) in &slots {
+            if !anchors.contains(&widget.style.anchor) {
+                anchors.push(widget.style.anchor);
+            }
+        }
+        for anchor in anchors {
+            let stack: Vec<_> = slots.iter()
+                .filter(|&&(widget, _, slot_height)| widget.style.anchor == anchor && slot_height > 0)
+                .collect();
+            let stack_height: u32 = stack.iter().map(|&&(_, _, slot_height)| slot_height).sum();
+            let (_, y_align) = anchor.alignment();
+            /* From the top, while viewports start at the bottom */
+            let mut top = (y_align * target_height.saturating_sub(stack_height) as f32) as u32;
+            for &&(widget, ref lines, slot_height) in &stack {
+                let viewport = Rect {
+                    left: 0,
+                    bottom: target_height.saturating_sub(top + slot_height),
+                    width: target_width,
+                    height: slot_height,
+                };
+                text.draw(&self.display, target, viewport, lines, &widget.style, widget.opacity);
+                top += slot_height;
+            }
+        }
+    }
+
+    /// Splits the target horizontally into one viewport per picture
+    fn render_slide(&self, target: &mut Frame, slide: &Slide, now: u64) {
+        let (target_width, target_height) = target.get_dimensions();
+        let count = slide.pictures.len() as u32;
+        let viewport_width = target_width / count;
+        for (i, (pic, state)) in slide.pictures.iter().enumerate() {
+            let viewport = Rect {
+                left: i as u32 * viewport_width,
+                bottom: 0,
+                width: viewport_width,
+                height: target_height,
+            };
+            self.render_picture(target, pic, state, viewport, now);
+            self.render_caption(target, pic, state, viewport, now);
+        }
+    }
+
+    fn render_caption(&self, target: &mut Frame, pic: &Picture, state: &PictureState, viewport: Rect, now: u64) {
+        let lines = pic.get_caption(&self.caption);
+        let alpha = if self.caption_fade {
+            state.get_alpha(now)
+        } else {
+            1.0
+        };
+        self.text.borrow_mut()
+            .draw(&self.display, target, viewport, &lines, &self.caption_style, alpha);
+    }
+
+    fn render_picture(&self, target: &mut Frame, pic: &Picture, state: &PictureState, viewport: Rect, now: u64) {
+        let target_aspect_ratio = viewport.width as f32 / viewport.height as f32;
         let mut matrix = [
             [1.0, 0.0, 0.0, 0.0],
             [0.0, 1.0, 0.0, 0.0],
@@ -272,9 +913,20 @@ impl<'a> Renderer<'a> {
             matrix[0][0] *= texture_aspect_ratio / target_aspect_ratio;
         };
         /* Zoom */
-        let zoom = state.get_zoom();
+        let zoom = state.get_zoom(now);
         matrix[0][0] *= zoom;
         matrix[1][1] *= zoom;
+        /* Move the focal point towards the center as far as the
+         * zoom allows without black borders
+         */
+        if let Some((focus_x, focus_y)) = pic.info.options.focus {
+            let x = 2.0 * focus_x - 1.0;
+            let y = 1.0 - 2.0 * focus_y;
+            let slack_x = matrix[0][0] - 1.0;
+            let slack_y = matrix[1][1] - 1.0;
+            matrix[3][0] = (-x * matrix[0][0]).max(-slack_x).min(slack_x);
+            matrix[3][1] = (-y * matrix[1][1]).max(-slack_y).min(slack_y);
+        }
         let params = DrawParameters {
             depth: Depth {
                 test: DepthTest::Overwrite,
@@ -282,14 +934,195 @@ impl<'a> Renderer<'a> {
                 .. Default::default()
             },
             blend: Blend::alpha_blending(),
+            /* Everything outside [-1, 1] gets clipped to the viewport */
+            viewport: Some(viewport),
             .. Default::default()
         };
+        let alpha = state.get_alpha(now);
+        draw_tiles(target, &self.program, &pic.tiles, matrix, alpha, self.sampling, &params);
+    }
+}
+
+/// How pictures get filtered when zoomed
+#[derive(Clone, Copy)]
+pub struct Sampling {
+    pub filter: Filter,
+    pub anisotropy: u16,
+}
+
+/// Shader for the tiles of a picture
+pub fn picture_program<F: Facade>(facade: &F) -> Program {
+    let vertex_shader_src = r#"
+        #version 140
+
+        in vec3 position;
+        in vec2 tex_coords;
+
+        out vec2 v_tex_coords;
+
+        uniform mat4 matrix;
+
+        void main() {
+            v_tex_coords = tex_coords;
+            gl_Position = matrix * vec4(position, 1.0);
+        }
+    "#;
+
+    let fragment_shader_src = r#"
+        #version 140
+
+        in vec2 v_tex_coords;
+        uniform float alpha;
+
+        uniform sampler2D tex;
+
+        out vec4 frag_color;
+
+        void main() {
+            frag_color = texture(tex, v_tex_coords);
+            frag_color.a = alpha;
+        }
+    "#;
+
+    Program::from_source(facade, vertex_shader_src, fragment_shader_src, None).unwrap()
+}
+
+/// Draws a picture that spans [-1, 1] before `matrix`
+pub fn draw_tiles<S: Surface>(target: &mut S, program: &Program, tiles: &[Tile], matrix: [[f32; 4]; 4], alpha: f32, sampling: Sampling, params: &DrawParameters) {
+    let minify_filter = match sampling.filter {
+        Filter::Nearest => MinifySamplerFilter::Nearest,
+        Filter::Bilinear => MinifySamplerFilter::Linear,
+        Filter::Trilinear => MinifySamplerFilter::LinearMipmapLinear,
+    };
+    let magnify_filter = match sampling.filter {
+        Filter::Nearest => MagnifySamplerFilter::Nearest,
+        _ => MagnifySamplerFilter::Linear,
+    };
+    for tile in tiles {
+        let tex = tile.texture.sampled()
+            .wrap_function(SamplerWrapFunction::Clamp)
+            .minify_filter(minify_filter)
+            .magnify_filter(magnify_filter)
+            .anisotropy(sampling.anisotropy);
         target.draw(
-            &shape,
+            &tile.shape,
             NoIndices(PrimitiveType::TriangleStrip),
-            &self.program,
-            &uniform! { matrix: matrix, tex: &pic.texture, alpha: state.get_alpha() as f32 },
-            &params
+            program,
+            &uniform! { matrix: matrix, tex: tex, alpha: alpha },
+            params
         ).unwrap();
     }
 }
+
+/// Records what is still on screen, and the loader no longer scales
+/// pictures for the closed window
+impl Drop for Renderer {
+    fn drop(&mut self) {
+        let now = self.clock.now();
+        for slide in self.current.iter().chain(self.next.iter()) {
+            self.record(slide, now);
+        }
+        let mut screen_sizes = self.screen_sizes.lock().unwrap();
+        screen_sizes.remove(&self.screen);
+        *self.display_size.lock().unwrap() = largest_size(&screen_sizes);
+    }
+}
+
+fn largest_size(sizes: &HashMap<usize, (u32, u32)>) -> (u32, u32) {
+    sizes.values()
+        .fold((0, 0), |(width, height), size| (width.max(size.0), height.max(size.1)))
+}
+
+/// Loads a picture to jump to once for all the screens it goes to,
+/// scaled for the largest of them, and sends it back with their
+/// screen indices for Renderer::jump()
+pub fn spawn_jump(renderers: &[Renderer], screens: Vec<usize>, source: String, jumps_tx: Sender<(Vec<usize>, LoadedPicture)>) {
+    let targets: Vec<&Renderer> = renderers.iter()
+        .filter(|renderer| screens.contains(&renderer.screen))
+        .collect();
+    let first = match targets.first() {
+        Some(first) => first,
+        None => return,
+    };
+    if source == pipe::PICTURE_SOURCE {
+        println!("Cannot jump to {}, it was piped", source);
+        return
+    }
+    let mut job = Job::for_source(&source);
+    job.limits = first.limits;
+    job.info.options.request = first.request.clone();
+    let mut display_size = (0, 0);
+    for renderer in &targets {
+        renderer.canceller.cancel_source(&source);
+        let size = *renderer.display_size.lock().unwrap();
+        display_size = (display_size.0.max(size.0), display_size.1.max(size.1));
+    }
+    println!("Jumping to {}", source);
+    thread::spawn(move|| {
+        if let Some(loaded) = job.run(display_size) {
+            let _ = jumps_tx.send((screens, loaded));
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use glium::{DrawParameters, Surface};
+    use glium::texture::{RawImage2d, Texture2d};
+    use config::Filter;
+    use testing;
+    use tiles::TiledUpload;
+    use super::*;
+
+    /// Mean difference per channel between two frames of a 1 px
+    /// checkerboard shrunk 8 times, the second zoomed in a little
+    fn shimmer(filter: Filter) -> f64 {
+        let display = testing::headless(256, 256);
+        let size = 2048;
+        let mut data = Vec::with_capacity(4 * size * size);
+        for y in 0..size {
+            for x in 0..size {
+                let value = if (x + y) % 2 == 0 { 255 } else { 0 };
+                data.extend_from_slice(&[value, value, value, 255]);
+            }
+        }
+        let image = RawImage2d::from_raw_rgba(data, (size as u32, size as u32));
+        let mut upload = TiledUpload::new(image, filter.needs_mipmaps());
+        while !upload.is_done() {
+            upload.step(&display, 0);
+        }
+        let tiles = upload.into_tiles();
+        let program = picture_program(&display);
+        let sampling = Sampling { filter, anisotropy: 1 };
+
+        let frames: Vec<Vec<u8>> = [1.0, 1.003f32].iter().map(|&zoom| {
+            let target = Texture2d::empty(&display, 256, 256).unwrap();
+            let matrix = [
+                [zoom, 0.0, 0.0, 0.0],
+                [0.0, zoom, 0.0, 0.0],
+                [0.0, 0.0, 1.0, 0.0],
+                [0.0, 0.0, 0.0, 1.0f32]
+            ];
+            let mut surface = target.as_surface();
+            surface.clear_color(0.0, 0.0, 0.0, 1.0);
+            draw_tiles(&mut surface, &program, &tiles, matrix, 1.0, sampling, &DrawParameters::default());
+            let frame: RawImage2d<u8> = target.read();
+            frame.data.into_owned()
+        }).collect();
+        /* RGBA, the alpha is always 255 */
+        let sum: u64 = frames[0].iter().zip(frames[1].iter())
+            .map(|(&a, &b)| (a as i32 - b as i32).unsigned_abs() as u64)
+            .sum();
+        sum as f64 / (256.0 * 256.0 * 3.0)
+    }
+
+    #[test]
+    #[ignore = "needs an offscreen OpenGL context, run with --ignored"]
+    fn trilinear_does_not_shimmer() {
+        let nearest = shimmer(Filter::Nearest);
+        let trilinear = shimmer(Filter::Trilinear);
+        /* Nearest flips between black and white all over */
+        assert!(nearest > 50.0, "nearest {}", nearest);
+        assert!(trilinear * 10.0 < nearest, "trilinear {}, nearest {}", trilinear, nearest);
+    }
+}
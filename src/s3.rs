@@ -0,0 +1,308 @@
+use std::env;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use sha2::{Digest, Sha256};
+use hmac::{Hmac, Mac};
+use hyper::{Method, Request, Uri};
+use treexml;
+use time;
+
+use http;
+use source::read_all;
+use tls::TlsOptions;
+
+/// SHA-256 of an empty payload
+const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+/// Credentials and endpoint of S3 or a compatible server
+///
+/// Buckets are always addressed by path.
+pub struct S3Client {
+    endpoint: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+    session_token: Option<String>,
+    tls: TlsOptions,
+}
+
+impl S3Client {
+    pub fn new(endpoint: &str, region: &str, access_key: &str, secret_key: &str, session_token: Option<String>, tls: TlsOptions) -> Self {
+        S3Client {
+            endpoint: endpoint.trim_end_matches('/').to_owned(),
+            region: region.to_owned(),
+            access_key: access_key.to_owned(),
+            secret_key: secret_key.to_owned(),
+            session_token,
+            tls,
+        }
+    }
+
+    /// From the usual AWS environment variables, `AWS_ENDPOINT_URL`
+    /// points to S3-compatible servers like MinIO
+    pub fn from_env(tls: TlsOptions) -> Result<Self, String> {
+        let var = |name: &str| env::var(name).ok()
+            .filter(|value| !value.is_empty());
+        let region = var("AWS_REGION")
+            .or_else(|| var("AWS_DEFAULT_REGION"))
+            .unwrap_or_else(|| "us-east-1".to_owned());
+        let endpoint = var("AWS_ENDPOINT_URL_S3")
+            .or_else(|| var("AWS_ENDPOINT_URL"))
+            .unwrap_or_else(|| format!("https://s3.{}.amazonaws.com", region));
+        let access_key = var("AWS_ACCESS_KEY_ID")
+                         .ok_or("AWS_ACCESS_KEY_ID is not set")?;
+        let secret_key = var("AWS_SECRET_ACCESS_KEY")
+                         .ok_or("AWS_SECRET_ACCESS_KEY is not set")?;
+        Ok(S3Client::new(&endpoint, &region, &access_key, &secret_key, var("AWS_SESSION_TOKEN"), tls))
+    }
+
+    /// Keys below a prefix, following continuation tokens
+    ///
+    /// max_bytes: limit for each page of the listing
+    /// cancel: gives up on the listing once set
+    pub fn list(&self, bucket: &str, prefix: &str, max_bytes: u64, cancel: &Arc<AtomicBool>) -> Result<Vec<String>, String> {
+        let mut keys = vec![];
+        let mut continuation = None;
+        loop {
+            let mut query = vec![
+                ("list-type".to_owned(), "2".to_owned()),
+                ("prefix".to_owned(), prefix.to_owned()),
+            ];
+            if let Some(token) = continuation.take() {
+                query.push(("continuation-token".to_owned(), token));
+            }
+            let req = self.request(bucket, "", &query)?;
+            let res = http::wait(http::request_async(req, &self.tls), cancel)
+                .map_err(|e| format!("{}", e))?;
+            if !res.status().is_success() {
+                return Err(format!("Listing s3://{}/{}: HTTP {}", bucket, prefix, res.status()))
+            }
+            let body = http::wait(res.read_body(max_bytes, cancel.clone()), cancel)
+                .map_err(|e| format!("Error reading bucket listing: {}", e))?;
+            let root = match treexml::Document::parse(&body[..]) {
+                Ok(treexml::Document { root: Some(root), .. }) => root,
+                Ok(_) => return Err("Empty bucket listing".to_owned()),
+                Err(e) => return Err(format!("Error parsing bucket listing: {}", e)),
+            };
+            for contents in root.filter_children(|el| el.name == "Contents") {
+                if let Some(key) = text(contents, "Key") {
+                    keys.push(key);
+                }
+            }
+            match (text(&root, "IsTruncated"), text(&root, "NextContinuationToken")) {
+                (Some(ref truncated), Some(token)) if truncated == "true" =>
+                    continuation = Some(token),
+                _ =>
+                    return Ok(keys),
+            }
+        }
+    }
+
+    pub fn get_object(&self, bucket: &str, key: &str, max_bytes: u64) -> Result<Vec<u8>, String> {
+        let req = self.request(bucket, key, &[])?;
+        let res = http::request(req, &self.tls)
+            .map_err(|e| format!("{}", e))?;
+        if !res.status().is_success() {
+            return Err(format!("s3://{}/{}: HTTP {}", bucket, key, res.status()))
+        }
+        res.body_limited(max_bytes)
+            .and_then(read_all)
+            .map_err(|e| format!("{}", e))
+    }
+
+    /// A GET signed with AWS Signature Version 4
+    fn request(&self, bucket: &str, key: &str, query: &[(String, String)]) -> Result<Request, String> {
+        let path = format!("/{}/{}", bucket, key);
+        let mut query: Vec<(String, String)> = query.iter()
+            .map(|(k, v)| (uri_encode(k, true), uri_encode(v, true)))
+            .collect();
+        query.sort();
+        let query = query.iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<String>>()
+            .join("&");
+        let endpoint: Uri = self.endpoint.parse()
+                            .map_err(|e| format!("Invalid S3 endpoint {}: {}", self.endpoint, e))?;
+        let host = endpoint.authority()
+                   .ok_or_else(|| format!("Invalid S3 endpoint {}", self.endpoint))?
+            .to_owned();
+        /* Path of the endpoint itself, if it is behind a proxy */
+        let canonical_uri = format!("{}{}", endpoint.path().trim_end_matches('/'), uri_encode(&path, false));
+
+        let now = time::now_utc();
+        let amz_date = time::strftime("%Y%m%dT%H%M%SZ", &now).unwrap();
+        let date = time::strftime("%Y%m%d", &now).unwrap();
+
+        let mut headers = vec![
+            ("host", host.clone()),
+            ("x-amz-content-sha256", EMPTY_SHA256.to_owned()),
+            ("x-amz-date", amz_date.clone()),
+        ];
+        if let Some(ref token) = self.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let canonical_headers: String = headers.iter()
+            .map(|&(name, ref value)| format!("{}:{}\n", name, value))
+            .collect();
+        let signed_headers = headers.iter()
+            .map(|&(name, _)| name)
+            .collect::<Vec<&str>>()
+            .join(";");
+        let canonical_request = format!("GET\n{}\n{}\n{}\n{}\n{}",
+                                        canonical_uri, query, canonical_headers,
+                                        signed_headers, EMPTY_SHA256);
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}",
+                                     amz_date, scope, sha256_hex(canonical_request.as_bytes()));
+        let mut key = hmac(format!("AWS4{}", self.secret_key).as_bytes(), date.as_bytes());
+        for part in &[self.region.as_str(), "s3", "aws4_request"] {
+            key = hmac(&key, part.as_bytes());
+        }
+        let signature = hex(&hmac(&key, string_to_sign.as_bytes()));
+        let authorization = format!("AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                                    self.access_key, scope, signed_headers, signature);
+
+        let url = if query.is_empty() {
+            format!("{}://{}{}", endpoint.scheme().unwrap_or("https"), host, canonical_uri)
+        } else {
+            format!("{}://{}{}?{}", endpoint.scheme().unwrap_or("https"), host, canonical_uri, query)
+        };
+        let uri = url.parse()
+                  .map_err(|e| format!("Invalid URL {}: {}", url, e))?;
+        let mut req = Request::new(Method::Get, uri);
+        for &(name, ref value) in headers.iter().filter(|&&(name, _)| name != "host") {
+            req.headers_mut().set_raw(name, value.clone());
+        }
+        req.headers_mut().set_raw("Authorization", authorization);
+        Ok(req)
+    }
+}
+
+/// Splits `s3://bucket/prefix`
+pub fn parse_url(url: &str) -> Option<(&str, &str)> {
+    if !url.starts_with("s3://") {
+        return None
+    }
+    let rest = &url["s3://".len()..];
+    let mut parts = rest.splitn(2, '/');
+    match (parts.next(), parts.next()) {
+        (Some(bucket), prefix) if !bucket.is_empty() =>
+            Some((bucket, prefix.unwrap_or(""))),
+        _ =>
+            None,
+    }
+}
+
+fn text(el: &treexml::Element, name: &str) -> Option<String> {
+    el.find_child(|child| child.name == name)
+        .and_then(|child| child.text.clone())
+}
+
+/// Percent-encodes everything but unreserved characters, as SigV4
+/// requires
+fn uri_encode(s: &str, encode_slash: bool) -> String {
+    let mut encoded = String::new();
+    for byte in s.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' =>
+                encoded.push(byte as char),
+            b'/' if !encode_slash =>
+                encoded.push('/'),
+            _ =>
+                encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    hex(&Sha256::digest(data))
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key)
+        .expect("HMAC takes keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing::{self, serve};
+
+    #[test]
+    fn signing_key() {
+        assert_eq!(sha256_hex(b""), EMPTY_SHA256);
+        /* From the AWS documentation on deriving a signing key */
+        let mut key = hmac(b"AWS4wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY", b"20120215");
+        for part in &["us-east-1", "iam", "aws4_request"] {
+            key = hmac(&key, part.as_bytes());
+        }
+        assert_eq!(hex(&key), "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d");
+    }
+
+    #[test]
+    fn urls() {
+        assert_eq!(parse_url("s3://photos/2017/"), Some(("photos", "2017/")));
+        assert_eq!(parse_url("s3://photos"), Some(("photos", "")));
+        assert_eq!(parse_url("s3:///2017"), None);
+        assert_eq!(parse_url("http://photos/2017"), None);
+        assert_eq!(uri_encode("2017/a b+c~.jpg", false), "2017/a%20b%2Bc~.jpg");
+        assert_eq!(uri_encode("2017/", true), "2017%2F");
+    }
+
+    /// Pages through a listing and loads a picture from a stand-in that
+    /// only answers signed requests
+    #[test]
+    fn lists_pages_and_loads() {
+        let jpeg = testing::jpeg();
+        let addr = serve(move |req| {
+            let signed = req.header("Authorization")
+                .map(|auth| auth.starts_with("AWS4-HMAC-SHA256 Credential=AKIDTEST/") &&
+                     auth.contains("/us-west-2/s3/aws4_request, SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature="))
+                .unwrap_or(false);
+            if !signed || req.header("x-amz-date").is_none() {
+                return (403, vec![])
+            }
+            let page = |keys: &[&str], next: Option<&str>| {
+                let mut xml = "<ListBucketResult>".to_owned();
+                for key in keys {
+                    xml.push_str(&format!("<Contents><Key>{}</Key></Contents>", key));
+                }
+                match next {
+                    Some(token) => xml.push_str(&format!("<IsTruncated>true</IsTruncated><NextContinuationToken>{}</NextContinuationToken>", token)),
+                    None => xml.push_str("<IsTruncated>false</IsTruncated>"),
+                }
+                xml.push_str("</ListBucketResult>");
+                (200, xml.into_bytes())
+            };
+            match req.path.as_str() {
+                "/photos/?list-type=2&prefix=2017%2F" =>
+                    page(&["2017/a.jpg", "2017/notes.txt", "2017/b.jpg"], Some("page2")),
+                "/photos/?continuation-token=page2&list-type=2&prefix=2017%2F" =>
+                    page(&["2017/c.jpg"], None),
+                "/photos/2017/a.jpg" | "/photos/2017/b.jpg" | "/photos/2017/c.jpg" =>
+                    (200, jpeg.clone()),
+                _ =>
+                    (404, vec![]),
+            }
+        });
+        let endpoint = format!("http://{}/", addr);
+        let client = S3Client::new(&endpoint, "us-west-2", "AKIDTEST", "secret", None, TlsOptions::default());
+        let cancel = Arc::new(AtomicBool::new(false));
+        assert_eq!(client.list("photos", "2017/", 4096, &cancel).unwrap(),
+                   vec!["2017/a.jpg", "2017/notes.txt", "2017/b.jpg", "2017/c.jpg"]);
+        assert_eq!(client.get_object("photos", "2017/c.jpg", 1 << 20).unwrap(), testing::jpeg());
+        assert!(client.get_object("photos", "2017/missing.jpg", 1 << 20).is_err());
+
+        let wrong_region = S3Client::new(&endpoint, "eu-west-1", "AKIDTEST", "secret", None, TlsOptions::default());
+        assert!(wrong_region.list("photos", "2017/", 4096, &cancel).is_err());
+    }
+}
@@ -0,0 +1,71 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::thread;
+use glium::texture::RawImage2d;
+use image::{ImageBuffer, Rgba};
+use image::imageops::flip_vertical;
+use time;
+
+use source::{Job, PictureInfo, SourceOptions};
+use config::Limits;
+use pipe;
+
+/// Writes a frame read back from the window, and optionally the
+/// untouched source images, in a background thread
+///
+/// Files are named `kenburns-YYYYmmdd-HHMMSS-MMM-SCREEN.png`, with
+/// milliseconds so that screenshots within a second don't overwrite
+/// each other, sources get `-source-N` appended.
+pub fn save(frame: RawImage2d<'static, u8>, dir: String, screen: usize, sources: Vec<PictureInfo>, limits: Limits) {
+    let now = time::now();
+    let timestamp = time::strftime("%Y%m%d-%H%M%S", &now).unwrap();
+    let name = format!("kenburns-{}-{:03}-{}", timestamp, now.tm_nsec / 1_000_000, screen);
+    thread::spawn(move|| {
+        let (width, height) = (frame.width, frame.height);
+        let image: Option<ImageBuffer<Rgba<u8>, Vec<u8>>> =
+            ImageBuffer::from_raw(width, height, frame.data.into_owned());
+        /* OpenGL rows go bottom to top */
+        let path = Path::new(&dir).join(format!("{}.png", name));
+        match image.map(|image| flip_vertical(&image).save(&path)) {
+            Some(Ok(())) => println!("Saved screenshot {}", path.display()),
+            Some(Err(e)) => println!("Error saving {}: {}", path.display(), e),
+            None => println!("Unexpected framebuffer size"),
+        }
+
+        for (i, info) in sources.iter().enumerate() {
+            let source = &info.source;
+            if source == pipe::PICTURE_SOURCE {
+                println!("Not saving {}, it was piped", source);
+                continue
+            }
+            /* Same headers and credentials, but no HTML pages */
+            let options = SourceOptions {
+                html_depth: Some(0),
+                .. info.options.clone()
+            };
+            let data = match Job::for_source(source).input.read(&limits, &options) {
+                Some(data) => data,
+                None => continue,
+            };
+            let path = Path::new(&dir).join(format!("{}-source-{}.{}", name, i, extension(source)));
+            match File::create(&path).and_then(|mut file| file.write_all(&data)) {
+                Ok(()) => println!("Saved source {}", path.display()),
+                Err(e) => println!("Error saving {}: {}", path.display(), e),
+            }
+        }
+    });
+}
+
+/// Keeps the source's file extension if it looks like one
+fn extension(source: &str) -> String {
+    let path = source.split(['?', '#'])
+        .next()
+        .unwrap_or(source);
+    match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+        Some(ext) if ext.len() <= 4 && ext.chars().all(|c| c.is_alphanumeric()) =>
+            ext.to_lowercase(),
+        _ =>
+            "jpg".to_owned(),
+    }
+}
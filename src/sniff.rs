@@ -0,0 +1,169 @@
+use hyper::mime::Mime;
+
+/// How many bytes of a response are looked at before deciding
+pub const SNIFF_LEN: usize = 512;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ContentKind {
+    Jpeg,
+    /// Other formats that the image crate decodes
+    Image,
+    Feed,
+    JsonFeed,
+    Html,
+    Unknown,
+}
+
+/// Magic numbers of picture formats
+const MAGIC: &[(&[u8], ContentKind)] = &[
+    (b"\xFF\xD8\xFF", ContentKind::Jpeg),
+    (b"\x89PNG\r\n\x1A\n", ContentKind::Image),
+    (b"GIF87a", ContentKind::Image),
+    (b"GIF89a", ContentKind::Image),
+    (b"II*\0", ContentKind::Image),
+    (b"MM\0*", ContentKind::Image),
+];
+
+/// Root elements of RSS, RDF and ATOM feeds
+const FEED_ROOTS: &[&str] = &["<rss", "<rdf:rdf", "<feed"];
+
+/// What the first bytes of a body look like, None if they say nothing
+pub fn sniff(data: &[u8]) -> Option<ContentKind> {
+    for &(magic, kind) in MAGIC {
+        if data.starts_with(magic) {
+            return Some(kind)
+        }
+    }
+    if data.len() >= 12 && &data[0..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        return Some(ContentKind::Image)
+    }
+
+    /* Markup and JSON, after any byte order mark and whitespace */
+    let data = if data.starts_with(b"\xEF\xBB\xBF") { &data[3..] } else { data };
+    let text = String::from_utf8_lossy(data).to_ascii_lowercase();
+    let text = text.trim_start();
+    if text.starts_with("{") {
+        return if text.contains("jsonfeed.org/version") {
+            Some(ContentKind::JsonFeed)
+        } else {
+            None
+        }
+    }
+    if !text.starts_with("<") {
+        return None
+    }
+    /* The root element may follow <?xml ?>, comments and a doctype */
+    if text.starts_with("<!doctype html") {
+        Some(ContentKind::Html)
+    } else if FEED_ROOTS.iter().any(|root| contains_tag(text, root)) {
+        Some(ContentKind::Feed)
+    } else if contains_tag(text, "<html") {
+        Some(ContentKind::Html)
+    } else {
+        None
+    }
+}
+
+/// Finds `<name` followed by the end of the tag name
+fn contains_tag(text: &str, tag: &str) -> bool {
+    text.match_indices(tag)
+        .any(|(i, _)| match text[i + tag.len()..].chars().next() {
+            Some(c) => c.is_whitespace() || c == '>' || c == '/',
+            None => true,
+        })
+}
+
+/// What the Content-Type claims
+pub fn from_mime(mime: &Mime) -> ContentKind {
+    let suffix = mime.suffix().map(|name| name.as_str());
+    match (mime.type_().as_str(), mime.subtype().as_str(), suffix) {
+        ("image", "jpeg", _) | ("image", "pjpeg", _) =>
+            ContentKind::Jpeg,
+        ("image", "png", _) | ("image", "gif", _) | ("image", "tiff", _) | ("image", "webp", _) =>
+            ContentKind::Image,
+        ("text", "html", _) =>
+            ContentKind::Html,
+        ("text", "xml", _) | ("application", "xml", _) | ("application", _, Some("xml")) =>
+            ContentKind::Feed,
+        ("application", "feed", Some("json")) =>
+            ContentKind::JsonFeed,
+        _ =>
+            ContentKind::Unknown,
+    }
+}
+
+/// The body has the last word, the header is only a hint
+pub fn content_kind(mime: Option<&Mime>, data: &[u8]) -> ContentKind {
+    let hint = mime.map(from_mime).unwrap_or(ContentKind::Unknown);
+    match sniff(data) {
+        Some(kind) => kind,
+        /* Don't trust images that don't look like one */
+        None if hint == ContentKind::Jpeg || hint == ContentKind::Image =>
+            ContentKind::Unknown,
+        None =>
+            hint,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniffs_magic_numbers_and_roots() {
+        let cases: &[(&[u8], Option<ContentKind>)] = &[
+            (b"\xFF\xD8\xFF\xE0\0\x10JFIF", Some(ContentKind::Jpeg)),
+            (b"\x89PNG\r\n\x1A\n\0\0\0\rIHDR", Some(ContentKind::Image)),
+            (b"GIF87a", Some(ContentKind::Image)),
+            (b"GIF89a\x01\0", Some(ContentKind::Image)),
+            (b"II*\0\x08\0\0\0", Some(ContentKind::Image)),
+            (b"MM\0*\0\0\0\x08", Some(ContentKind::Image)),
+            (b"RIFF\x24\0\0\0WEBPVP8 ", Some(ContentKind::Image)),
+            (b"RIFF\x24\0\0\0WAVEfmt ", None),
+            (b"<?xml version=\"1.0\"?>\n<rss version=\"2.0\"><channel>", Some(ContentKind::Feed)),
+            (b"\xEF\xBB\xBF<?xml version=\"1.0\"?><feed xmlns=\"http://www.w3.org/2005/Atom\">", Some(ContentKind::Feed)),
+            (b"<?xml version=\"1.0\"?><!-- comment --><rdf:RDF xmlns:rdf=\"\">", Some(ContentKind::Feed)),
+            (b"  <RSS>", Some(ContentKind::Feed)),
+            (b"<rssfeed>", None),
+            (b"<!DOCTYPE html><html><head>", Some(ContentKind::Html)),
+            (b"<html lang=\"en\">", Some(ContentKind::Html)),
+            (b"<?xml version=\"1.0\"?><svg>", None),
+            (b"{\"version\": \"https://jsonfeed.org/version/1.1\", \"items\": []}", Some(ContentKind::JsonFeed)),
+            (b"{\"items\": []}", None),
+            (b"plain text", None),
+            (b"", None),
+        ];
+        for &(data, expected) in cases {
+            assert_eq!(sniff(data), expected, "{:?}", String::from_utf8_lossy(data));
+        }
+    }
+
+    #[test]
+    fn header_is_a_hint() {
+        let jpeg: &[u8] = b"\xFF\xD8\xFF\xE0";
+        let rss: &[u8] = b"<rss version=\"2.0\">";
+        let cases: &[(Option<&str>, &[u8], ContentKind)] = &[
+            (None, jpeg, ContentKind::Jpeg),
+            (Some("application/octet-stream"), jpeg, ContentKind::Jpeg),
+            (Some("binary/octet-stream"), jpeg, ContentKind::Jpeg),
+            (Some("text/html"), jpeg, ContentKind::Jpeg),
+            (Some("image/pjpeg"), jpeg, ContentKind::Jpeg),
+            (Some("text/plain"), rss, ContentKind::Feed),
+            (Some("image/pjpeg"), b"", ContentKind::Unknown),
+            (Some("image/png"), b"<html>", ContentKind::Html),
+            (Some("image/jpeg"), b"not a picture", ContentKind::Unknown),
+            (Some("application/rss+xml"), b"", ContentKind::Feed),
+            (Some("application/atom+xml"), b"   ", ContentKind::Feed),
+            (Some("text/xml; charset=utf-8"), b"", ContentKind::Feed),
+            (Some("application/feed+json"), b"", ContentKind::JsonFeed),
+            (Some("text/html; charset=utf-8"), b"", ContentKind::Html),
+            (Some("application/octet-stream"), b"\0\0\0", ContentKind::Unknown),
+            (None, b"", ContentKind::Unknown),
+        ];
+        for &(mime, data, expected) in cases {
+            let mime: Option<Mime> = mime.map(|mime| mime.parse().unwrap());
+            assert_eq!(content_kind(mime.as_ref(), data), expected,
+                       "{:?} {:?}", mime, String::from_utf8_lossy(data));
+        }
+    }
+}
@@ -1,92 +1,821 @@
-use std::fs::{metadata, File, read_dir, DirEntry};
-use std::io::{BufReader, Read};
-use std::sync::mpsc::SyncSender;
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::fs::{canonicalize, metadata, File, read_dir, DirEntry};
+use std::io::{self, Read, Cursor, Chain};
+use std::mem::replace;
+use std::path::Path;
+use rand::{thread_rng, Rng};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
 use glium::texture::RawImage2d;
-use image::{jpeg, ImageDecoder, DynamicImage, ImageResult};
+use futures::{future, Future};
+use futures_cpupool::CpuPool;
+use image::{ImageDecoder, DynamicImage, GenericImage, ImageResult, ImageBuffer, ImageError, FilterType};
+use jpeg_decoder;
 use hyper::Uri;
 use hyper::header::ContentType;
-use hyper::mime::{IMAGE_JPEG, TEXT_XML, APPLICATION};
+use image::{png, gif, tiff, webp, guess_format, ImageFormat};
+use serde_json;
 use treexml;
+use exif;
+use time;
 
 use util::*;
-use http::get;
+use http::{self, get, get_async, Response, Body, RequestOptions};
+use html;
+use sniff::{self, ContentKind, SNIFF_LEN};
+use render::MAX_ZOOM;
+use prefetch::{Prefetcher, Canceller};
+use config::{Config, Limits};
+use dedup::{dhash, Dedup};
+use history::History;
+use favorites::Favorites;
+use playlist;
+use archive::{self, ArchiveKind};
+use pipe::{self, Stdin};
+use s3::{self, S3Client};
+use webdav;
 
-pub struct Loader<'a> {
-    tx: SyncSender<RawImage2d<'a, u8>>,
+/// Where a picture came from and how it was loaded
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct PictureInfo {
+    /// Path or URL
+    pub source: String,
+    /// Feed item title
+    pub title: Option<String>,
+    /// Feed item page
+    pub link: Option<String>,
+    /// Feed item author
+    pub author: Option<String>,
+    pub exif: ExifInfo,
+    /// Before downscaling
+    pub original_size: (u32, u32),
+    /// Time to fetch or read the file, in microseconds
+    pub fetch_time: u64,
+    /// Time to decode and scale, in microseconds
+    pub decode_time: u64,
+    /// Perceptual hash of the decoded picture, see dedup::dhash()
+    pub hash: u64,
+    pub options: SourceOptions,
 }
 
+/// Per-entry settings from playlist directives
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct SourceOptions {
+    /// Show duration in microseconds
+    pub duration: Option<u64>,
+    /// Replaces the configured caption fields
+    pub caption: Option<String>,
+    /// Point to keep in view while zooming, from (0, 0) at the top
+    /// left to (1, 1) at the bottom right
+    pub focus: Option<(f32, f32)>,
+    /// How many HTML pages to follow to their picture, 0 to drop
+    /// pages
+    pub html_depth: Option<u32>,
+    /// Never shown by the remote control, may carry credentials
+    #[serde(skip)]
+    pub request: RequestOptions,
+}
+
+impl SourceOptions {
+    /// Own settings, falling back to those of an enclosing playlist
+    /// entry
+    pub fn or(&self, outer: &SourceOptions) -> SourceOptions {
+        SourceOptions {
+            duration: self.duration.or(outer.duration),
+            caption: self.caption.clone().or_else(|| outer.caption.clone()),
+            focus: self.focus.or(outer.focus),
+            html_depth: self.html_depth.or(outer.html_depth),
+            request: self.request.or(&outer.request),
+        }
+    }
+}
+
+/// Fields rendered as text by the exif crate
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ExifInfo {
+    /// DateTimeOriginal
+    pub date: Option<String>,
+    pub make: Option<String>,
+    pub model: Option<String>,
+    pub exposure_time: Option<String>,
+    pub f_number: Option<String>,
+    pub iso: Option<String>,
+    pub focal_length: Option<String>,
+}
+
+impl ExifInfo {
+    pub fn read(data: &[u8]) -> Self {
+        let reader = match exif::Reader::new(&mut Cursor::new(data)) {
+            Ok(reader) => reader,
+            Err(_) => return ExifInfo::default(),
+        };
+        let get = |tag: exif::Tag| reader.get_field(tag, false)
+            .map(|field| format!("{}", field.value.display_as(field.tag)));
+        ExifInfo {
+            date: get(exif::Tag::DateTimeOriginal),
+            make: get(exif::Tag::Make),
+            model: get(exif::Tag::Model),
+            exposure_time: get(exif::Tag::ExposureTime),
+            f_number: get(exif::Tag::FNumber),
+            iso: get(exif::Tag::PhotographicSensitivity),
+            focal_length: get(exif::Tag::FocalLength),
+        }
+    }
+}
+
+/// A decoded picture on its way to the renderer
+pub struct LoadedPicture {
+    pub image: RawImage2d<'static, u8>,
+    pub info: PictureInfo,
+}
+
+impl Clone for LoadedPicture {
+    fn clone(&self) -> Self {
+        LoadedPicture {
+            image: RawImage2d {
+                data: Cow::Owned(self.image.data.to_vec()),
+                width: self.image.width,
+                height: self.image.height,
+                format: self.image.format,
+            },
+            info: self.info.clone(),
+        }
+    }
+}
+
+/// Everything that is slow about loading one picture, run by the
+/// prefetch workers
+#[derive(Clone)]
+pub struct Job {
+    pub input: Input,
+    pub info: PictureInfo,
+    pub limits: Limits,
+}
+
+#[derive(Clone)]
+pub enum Input {
+    /// Local JPEG file
+    File(String),
+    /// Picture inside a local ZIP or tar file
+    ArchiveEntry {
+        archive: String,
+        entry: String,
+    },
+    /// Feed item link that is expected to point to a JPEG
+    Url(String),
+    /// Picture piped to stdin, or already fetched while sniffing a URL
+    Data(Vec<u8>),
+}
+
+impl Input {
+    /// Fetches the untouched file contents
+    ///
+    /// options: html_depth and request options for URLs
+    pub fn read(self, limits: &Limits, options: &SourceOptions) -> Option<Vec<u8>> {
+        let html_depth = options.html_depth.unwrap_or(0);
+        let data = match self {
+            Input::File(filename) =>
+                match File::open(&filename).and_then(|file| read_limited(file, limits.max_bytes)) {
+                    Ok(data) => data,
+                    Err(e) => {
+                        println!("Error reading {}: {}", filename, e);
+                        return None
+                    }
+                },
+            Input::Url(ref url) if url.starts_with("s3://") => {
+                println!("GET {}", url);
+                let object = s3::parse_url(url)
+                    .ok_or_else(|| "Invalid S3 URL".to_owned())
+                    .and_then(|(bucket, key)| S3Client::from_env(options.request.tls.clone())
+                              .and_then(|client| client.get_object(bucket, key, limits.max_bytes)));
+                match object {
+                    Ok(data) => data,
+                    Err(e) => {
+                        println!("Error reading {}: {}", url, e);
+                        return None
+                    }
+                }
+            },
+            Input::Url(url) => {
+                println!("GET {}", url);
+                let res = if webdav::http_url(&url).is_some() {
+                    webdav::get(&url, &options.request)
+                } else {
+                    url.parse()
+                        .map_err(|e| format!("Invalid URL: {}", e))
+                        .and_then(|uri| get(&uri, &options.request).map_err(|e| format!("{}", e)))
+                };
+                let res = match res {
+                    Err(e) => {
+                        println!("{}", e);
+                        return None
+                    },
+                    Ok(res) => res
+                };
+                let (kind, body) = match sniff_response(res, limits.max_bytes) {
+                    Ok(sniffed) => sniffed,
+                    Err(e) => {
+                        println!("Error reading {}: {}", url, e);
+                        return None
+                    }
+                };
+                match kind {
+                    ContentKind::Jpeg | ContentKind::Image => {
+                        println!("Reading image til end...");
+                        match read_all(body) {
+                            Ok(data) => data,
+                            Err(e) => {
+                                println!("Error reading {}: {}", url, e);
+                                return None
+                            }
+                        }
+                    },
+                    ContentKind::Html if html_depth > 0 => {
+                        let options = SourceOptions {
+                            html_depth: Some(html_depth - 1),
+                            .. options.clone()
+                        };
+                        return follow_html(&url, body)
+                            .and_then(|image| Input::Url(image).read(limits, &options))
+                    },
+                    _ => {
+                        println!("Not an image: {}", url);
+                        return None
+                    }
+                }
+            },
+            Input::ArchiveEntry { archive, entry } =>
+                match archive::read_entry(&archive, &entry, limits.max_bytes) {
+                    Ok(data) => data,
+                    Err(e) => {
+                        println!("Error reading {}: {}", archive, e);
+                        return None
+                    }
+                },
+            Input::Data(data) =>
+                data,
+        };
+        Some(data)
+    }
+}
+
+impl Job {
+    pub fn new(input: Input, info: PictureInfo) -> Self {
+        Job {
+            input,
+            info,
+            limits: Limits::default(),
+        }
+    }
+
+    /// Loads a path or URL as one picture
+    pub fn for_source(source: &str) -> Self {
+        let input = if is_url(source) {
+                Input::Url(source.to_owned())
+            } else if let Some((archive, entry)) = archive::split_source(source) {
+                Input::ArchiveEntry {
+                    archive: archive.to_owned(),
+                    entry: entry.to_owned(),
+                }
+            } else {
+                Input::File(source.to_owned())
+            };
+        let info = PictureInfo {
+            source: source.to_owned(),
+            .. PictureInfo::default()
+        };
+        Job::new(input, info)
+    }
+
+    /// Fetches and decodes in the current thread
+    pub fn run(self, display_size: (u32, u32)) -> Option<LoadedPicture> {
+        let t1 = get_us();
+        let data = self.input.read(&self.limits, &self.info.options)?;
+        decode(self.info, &self.limits, data, display_size, get_us() - t1)
+    }
+
+    /// Like run() but without blocking the caller: HTTP is fetched on
+    /// the reactor, everything else is read and decoded on the pool
+    ///
+    /// Gives up between steps, and between chunks of a download, once
+    /// cancel is set.
+    pub fn load(self, pool: &CpuPool, display_size: Arc<Mutex<(u32, u32)>>, cancel: Arc<AtomicBool>) -> Box<dyn Future<Item=Option<LoadedPicture>, Error=()> + Send> {
+        let Job { input, info, limits } = self;
+        let t1 = get_us();
+        let data: Box<dyn Future<Item=Option<Vec<u8>>, Error=()> + Send> = match input {
+            Input::Url(ref url) if url.starts_with("http://") || url.starts_with("https://") =>
+                fetch_url(url.clone(), limits, info.options.clone(), cancel.clone()),
+            input => {
+                let options = info.options.clone();
+                let cancel = cancel.clone();
+                Box::new(pool.spawn_fn(move || -> Result<Option<Vec<u8>>, ()> {
+                    if cancel.load(Ordering::Relaxed) {
+                        Ok(None)
+                    } else {
+                        Ok(input.read(&limits, &options))
+                    }
+                }))
+            },
+        };
+        let pool = pool.clone();
+        Box::new(data.and_then(move |data| -> Box<dyn Future<Item=Option<LoadedPicture>, Error=()> + Send> {
+            let fetch_time = get_us() - t1;
+            match data {
+                Some(data) => Box::new(pool.spawn_fn(move || -> Result<Option<LoadedPicture>, ()> {
+                    if cancel.load(Ordering::Relaxed) {
+                        println!("Cancelled {}", info.source);
+                        return Ok(None)
+                    }
+                    let display_size = *display_size.lock().unwrap();
+                    Ok(decode(info, &limits, data, display_size, fetch_time))
+                })),
+                None =>
+                    Box::new(future::ok(None)),
+            }
+        }))
+    }
+}
+
+/// Decodes and scales fetched data, then fills in what was learned
+fn decode(mut info: PictureInfo, limits: &Limits, data: Vec<u8>, display_size: (u32, u32), fetch_time: u64) -> Option<LoadedPicture> {
+    let t1 = get_us();
+    info.exif = ExifInfo::read(&data);
+    let (image, original_size) = load_image(data, display_size, limits.max_pixels)?;
+    info.hash = dhash(&image.data, image.width, image.height);
+    info.original_size = original_size;
+    info.fetch_time = fetch_time;
+    info.decode_time = get_us() - t1;
+    Some(LoadedPicture {
+        image,
+        info,
+    })
+}
+
+/// Input::Url on the reactor: fetches, sniffs and follows HTML pages
+fn fetch_url(url: String, limits: Limits, options: SourceOptions, cancel: Arc<AtomicBool>) -> Box<dyn Future<Item=Option<Vec<u8>>, Error=()> + Send> {
+    if cancel.load(Ordering::Relaxed) {
+        return Box::new(future::ok(None))
+    }
+    println!("GET {}", url);
+    let uri: Uri = match url.parse() {
+        Ok(uri) => uri,
+        Err(e) => {
+            println!("Invalid URL {}: {}", url, e);
+            return Box::new(future::ok(None))
+        }
+    };
+    let html_depth = options.html_depth.unwrap_or(0);
+    let read_cancel = cancel.clone();
+    Box::new(get_async(&uri, &options.request)
+        .and_then(move |res| {
+            let mime = res.headers().get::<ContentType>()
+                .map(|ContentType(mime)| mime.clone());
+            res.read_body(limits.max_bytes, read_cancel)
+                .map(move |data| (mime, data))
+        })
+        .then(move |fetched| -> Box<dyn Future<Item=Option<Vec<u8>>, Error=()> + Send> {
+            let (mime, data) = match fetched {
+                Ok(fetched) => fetched,
+                Err(e) => {
+                    println!("Error reading {}: {}", url, e);
+                    return Box::new(future::ok(None))
+                }
+            };
+            let kind = sniff::content_kind(mime.as_ref(), &data[..data.len().min(SNIFF_LEN)]);
+            match kind {
+                ContentKind::Jpeg | ContentKind::Image =>
+                    Box::new(future::ok(Some(data))),
+                ContentKind::Html if html_depth > 0 =>
+                    match follow_html(&url, &data[..]) {
+                        Some(image) => {
+                            let options = SourceOptions {
+                                html_depth: Some(html_depth - 1),
+                                .. options
+                            };
+                            fetch_url(image, limits, options, cancel)
+                        },
+                        None =>
+                            Box::new(future::ok(None)),
+                    },
+                _ => {
+                    println!("Not an image: {}", url);
+                    Box::new(future::ok(None))
+                },
+            }
+        }))
+}
+
+/// Anything that Input::Url can fetch
+fn is_url(source: &str) -> bool {
+    source.starts_with("http://") ||
+        source.starts_with("https://") ||
+        source.starts_with("s3://") ||
+        webdav::http_url(source).is_some()
+}
+
+/// Picture file extensions, so that such URLs need not be sniffed
+/// before they are queued
+fn is_picture_url(url: &str) -> bool {
+    let path = url.split(['?', '#'])
+        .next()
+        .unwrap_or(url)
+        .to_lowercase();
+    [".jpg", ".jpeg", ".png", ".gif", ".webp", ".tif", ".tiff"].iter()
+        .any(|ext| path.ends_with(ext))
+}
+
+/// The picture an HTML page is about, see html::find_image()
+fn follow_html<R: Read>(page: &str, body: R) -> Option<String> {
+    let body = match read_all(body) {
+        Ok(body) => body,
+        Err(e) => {
+            println!("Error reading {}: {}", page, e);
+            return None
+        }
+    };
+    let image = html::find_image(&String::from_utf8_lossy(&body))
+        .and_then(|image| page.parse().ok()
+                  .and_then(|base| uri_join(&base, &image)));
+    match image {
+        Some(ref image) => println!("Following {} to {}", page, image),
+        None => println!("No picture found on {}", page),
+    }
+    image
+}
+
+pub fn read_all<R: Read>(mut reader: R) -> io::Result<Vec<u8>> {
+    let mut data = vec![];
+    reader.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Like read_all() but fails beyond max_bytes, whatever sizes a
+/// header may claim
+pub fn read_limited<R: Read>(reader: R, max_bytes: u64) -> io::Result<Vec<u8>> {
+    let data = read_all(reader.take(max_bytes.saturating_add(1)))?;
+    if data.len() as u64 > max_bytes {
+        Err(io::Error::other(format!("Larger than {} bytes", max_bytes)))
+    } else {
+        Ok(data)
+    }
+}
+
+/// The sniffed start of a response followed by the rest of it
+type SniffedBody = Chain<Cursor<Vec<u8>>, Body>;
+
+/// Reads the start of a response to find out what it is, returns
+/// that along with the whole body
+///
+/// Blocks, for the workers. The loader uses Response::read_start().
+fn sniff_response(res: Response, max_bytes: u64) -> io::Result<(ContentKind, SniffedBody)> {
+    let mime = res.headers().get::<ContentType>()
+        .map(|ContentType(mime)| mime.clone());
+    println!("Content-Type: {:?}", mime);
+    let mut body = res.body_limited(max_bytes)?;
+    let mut prefix = vec![0; SNIFF_LEN];
+    let mut len = 0;
+    while len < prefix.len() {
+        match body.read(&mut prefix[len..])? {
+            0 => break,
+            read => len += read,
+        }
+    }
+    prefix.truncate(len);
+    let kind = sniff::content_kind(mime.as_ref(), &prefix);
+    if kind == ContentKind::Unknown {
+        println!("Cannot handle content-type {:?}", mime);
+    }
+    Ok((kind, Cursor::new(prefix).chain(body)))
+}
+
+/// What renderers and the remote control need of a loader thread
+#[derive(Clone)]
+pub struct LoaderHandle {
+    /// Shared by all screens using this loader
+    pub source_rx: Arc<Mutex<Receiver<LoadedPicture>>>,
+    /// Largest framebuffer dimensions of the screens, updated by the
+    /// renderers
+    pub display_size: Arc<Mutex<(u32, u32)>>,
+    /// Framebuffer dimensions of each open screen by index
+    pub screen_sizes: Arc<Mutex<HashMap<usize, (u32, u32)>>>,
+    /// Iterated by the loader, may be changed while running
+    pub sources: Arc<Mutex<Vec<String>>>,
+    /// Submitted to the prefetcher but not yet taken by a renderer
+    pub queue: Arc<Mutex<VecDeque<String>>>,
+    pub canceller: Canceller,
+}
+
+pub struct Loader {
+    prefetcher: Prefetcher,
+    /// Top-level sources, may be changed while running
+    sources: Arc<Mutex<Vec<String>>>,
+    history: Option<Arc<Mutex<History>>>,
+    /// Skip pictures until this one in the first loop
+    resume: RefCell<Option<String>>,
+    /// Seconds within which pictures count as recently seen, 0 to
+    /// show everything
+    recent: i64,
+    /// Nothing was submitted in the last loop, so show recently seen
+    /// pictures too
+    include_recent: Cell<bool>,
+    /// In the current loop, not counting repeats
+    submitted: Cell<usize>,
+    /// Pictures submitted in the previous loop
+    loop_length: Cell<usize>,
+    /// All pictures submitted, including repeats
+    total: Cell<usize>,
+    favorites: Arc<Mutex<Favorites>>,
+    /// How many times starred pictures are shown per loop
+    star_weight: usize,
+    /// Starred pictures to show again once `total` reaches a value,
+    /// loaded like they were first
+    repeats: RefCell<Vec<(usize, Job)>>,
+    /// Options and title of the playlist entry being loaded
+    entry: RefCell<(SourceOptions, Option<String>)>,
+    /// Nested playlists being loaded, to detect loops
+    playlists: RefCell<Vec<String>>,
+    /// Randomize directory and archive listings
+    shuffle: bool,
+    /// Started when `-` is first loaded
+    stdin: RefCell<Option<Arc<Mutex<Stdin>>>>,
+    /// Named pipes being read
+    fifos: RefCell<Vec<String>>,
+    /// Entries from named pipes, loaded before anything else
+    pending: Arc<Mutex<VecDeque<String>>>,
+    draining: Cell<bool>,
+    /// Default for SourceOptions::html_depth
+    html_depth: u32,
+    limits: Limits,
+    /// Headers and credentials from the config
+    request: RequestOptions,
+    /// Top-level source being loaded, to cancel its jobs when it is
+    /// removed
+    root: RefCell<String>,
+    /// Stops the requests made while walking root
+    cancel: RefCell<Arc<AtomicBool>>,
+}
+
+/// Spacing of starred repeats before the length of a loop is known
+const FIRST_LOOP_LENGTH: usize = 20;
+/// Milliseconds between checks for piped entries while there is
+/// nothing else to load
+const PIPE_POLL_INTERVAL: u64 = 100;
+
 /**
  * * run*() methods: iterate over files
- * * load*() methods: load one file
+ * * load*() methods: queue one file for the prefetcher
  **/
-impl<'a> Loader<'a> {
-    pub fn new(tx: SyncSender<RawImage2d<'a, u8>>) -> Loader<'a> {
+impl Loader {
+    pub fn new(prefetcher: Prefetcher, config: &Config, sources: Arc<Mutex<Vec<String>>>, history: Option<Arc<Mutex<History>>>, favorites: Arc<Mutex<Favorites>>, resume: Option<String>) -> Loader {
         Loader {
-            tx: tx,
+            prefetcher,
+            sources,
+            history,
+            resume: RefCell::new(resume),
+            recent: (config.recent * 3600.0) as i64,
+            include_recent: Cell::new(false),
+            submitted: Cell::new(0),
+            loop_length: Cell::new(0),
+            total: Cell::new(0),
+            favorites,
+            star_weight: config.star_weight,
+            repeats: RefCell::new(vec![]),
+            entry: RefCell::new((SourceOptions::default(), None)),
+            playlists: RefCell::new(vec![]),
+            shuffle: config.shuffle,
+            stdin: RefCell::new(None),
+            fifos: RefCell::new(vec![]),
+            pending: Arc::new(Mutex::new(VecDeque::new())),
+            draining: Cell::new(false),
+            html_depth: config.html_depth,
+            limits: config.limits,
+            request: config.request.clone(),
+            root: RefCell::new(String::new()),
+            cancel: RefCell::new(Arc::new(AtomicBool::new(false))),
         }
     }
 
-    pub fn run_loop(&self, filenames: Vec<String>) {
+    /// Starts a loader thread, its pictures can be taken by several
+    /// renderers
+    ///
+    /// dedup: shared by all loaders to skip pictures already shown by
+    ///   another
+    /// resume: source to continue after, from the history
+    pub fn spawn(config: &Config, filenames: Vec<String>, dedup: Option<Arc<Mutex<Dedup>>>, history: Option<Arc<Mutex<History>>>, favorites: Arc<Mutex<Favorites>>, resume: Option<String>) -> LoaderHandle {
+        /* Rendezvous channel: the prefetcher holds all decoded pictures */
+        let (source_tx, source_rx) = sync_channel(0);
+        let display_size = Arc::new(Mutex::new((0, 0)));
+        let prefetcher = Prefetcher::new(config.workers, config.prefetch, source_tx, display_size.clone(), dedup, favorites.clone());
+        let handle = LoaderHandle {
+            source_rx: Arc::new(Mutex::new(source_rx)),
+            display_size,
+            screen_sizes: Arc::new(Mutex::new(HashMap::new())),
+            sources: Arc::new(Mutex::new(filenames)),
+            queue: prefetcher.get_queue(),
+            canceller: prefetcher.get_canceller(),
+        };
+        let sources = handle.sources.clone();
+        let loader = Loader::new(prefetcher, config, sources, history, favorites, resume);
+        thread::spawn(move|| {
+            loader.run_loop();
+        });
+        handle
+    }
+
+    pub fn run_loop(&self) {
         loop {
-            for filename in &filenames {
-                self.run_filename(filename);
+            /* By name, sources may be added and removed meanwhile */
+            let mut done: Vec<String> = vec![];
+            loop {
+                /* Don't hold the lock while loading */
+                let filename = match self.sources.lock().unwrap().iter().find(|source| !done.contains(source)) {
+                    Some(filename) => filename.clone(),
+                    None => break,
+                };
+                done.push(filename.clone());
+                self.set_root(filename.clone(), Arc::new(AtomicBool::new(false)));
+                self.run_filename(&filename);
+            }
+            if self.submitted.get() == 0 {
+                /* Wait for sources to be added or piped */
+                self.wait_pending();
             }
+
+            if let Some(resume) = self.resume.borrow_mut().take() {
+                println!("Did not find {} to resume after", resume);
+            }
+            self.include_recent.set(self.submitted.get() == 0);
+            self.loop_length.set(self.submitted.get());
+            self.submitted.set(0);
+        }
+    }
+
+    fn set_root(&self, root: String, cancel: Arc<AtomicBool>) {
+        self.prefetcher.set_listing(&root, cancel.clone());
+        *self.root.borrow_mut() = root;
+        *self.cancel.borrow_mut() = cancel;
+    }
+
+    /// Waits for a request without blocking the reactor, see
+    /// http::wait()
+    fn wait<F: Future<Error=hyper::Error>>(&self, future: F) -> Result<F::Item, hyper::Error> {
+        let cancel = self.cancel.borrow().clone();
+        let result = http::wait(future, &cancel);
+        self.after_cancel();
+        result
+    }
+
+    /// A request was skipped, the next one of the same root may go
+    /// ahead unless the root was removed
+    fn after_cancel(&self) {
+        let cancel = self.cancel.borrow();
+        if cancel.load(Ordering::Relaxed) &&
+            (self.draining.get() || self.sources.lock().unwrap().contains(&self.root.borrow())) {
+                cancel.store(false, Ordering::Relaxed);
+            }
+    }
+
+    /// Skips pictures before the resume position, banned ones and
+    /// those seen recently
+    fn submit(&self, mut job: Job) {
+        self.run_pending();
+        /* Piped entries are not in the sources */
+        if !self.draining.get() && !self.sources.lock().unwrap().contains(&self.root.borrow()) {
+            println!("Skipping {}, source removed", job.info.source);
+            return
+        }
+        {
+            let entry = self.entry.borrow();
+            job.info.options = job.info.options.or(&entry.0);
+            if job.info.options.html_depth.is_none() {
+                job.info.options.html_depth = Some(self.html_depth);
+            }
+            job.info.options.request = job.info.options.request.or(&self.request);
+            job.limits = self.limits;
+            if job.info.title.is_none() {
+                job.info.title = entry.1.clone();
+            }
+        }
+        let source = job.info.source.clone();
+        let resume = self.resume.borrow().clone();
+        if let Some(resume) = resume {
+            if source == resume {
+                println!("Resuming after {}", source);
+                *self.resume.borrow_mut() = None;
+            }
+            return
+        }
+
+        let (banned, starred) = {
+            let favorites = self.favorites.lock().unwrap();
+            (favorites.is_banned_source(&source), favorites.is_starred(&source))
+        };
+        if banned {
+            println!("Skipping {}, banned", source);
+            return
+        }
+
+        if !starred && self.recent > 0 && !self.include_recent.get() {
+            let last_shown = self.history.as_ref()
+                .and_then(|history| history.lock().unwrap().last_shown(&source));
+            if let Some(last_shown) = last_shown {
+                if time::get_time().sec - last_shown < self.recent {
+                    println!("Skipping {}, seen recently", source);
+                    return
+                }
+            }
+        }
+
+        self.submitted.set(self.submitted.get() + 1);
+        self.total.set(self.total.get() + 1);
+        if starred {
+            self.schedule_repeats(&job);
+        }
+        self.prefetcher.submit(job, &self.root.borrow());
+        self.submit_repeats();
+    }
+
+    /// Spreads further showings of a starred picture over the next
+    /// loop
+    fn schedule_repeats(&self, job: &Job) {
+        let loop_length = match self.loop_length.get() {
+            0 => FIRST_LOOP_LENGTH,
+            loop_length => loop_length,
+        };
+        let spacing = (loop_length / self.star_weight).max(1);
+        let mut repeats = self.repeats.borrow_mut();
+        for i in 1..self.star_weight {
+            repeats.push((self.total.get() + i * spacing, job.clone()));
+        }
+    }
+
+    fn submit_repeats(&self) {
+        loop {
+            let due = {
+                let mut repeats = self.repeats.borrow_mut();
+                match repeats.iter().position(|&(at, _)| at <= self.total.get()) {
+                    Some(index) => repeats.remove(index).1,
+                    None => return,
+                }
+            };
+            let favorites = self.favorites.lock().unwrap();
+            if !favorites.is_starred(&due.info.source) || favorites.is_banned_source(&due.info.source) {
+                continue
+            }
+            drop(favorites);
+            self.total.set(self.total.get() + 1);
+            self.prefetcher.submit(due, &self.root.borrow());
         }
     }
 
 
     pub fn run_filename(&self, filename: &str) {
-        if filename.starts_with("http://") ||
+        if filename == "-" {
+            self.run_stdin()
+        } else if filename == pipe::PICTURE_SOURCE {
+            self.run_stdin_picture()
+        } else if let Some(path) = filename.strip_prefix("fifo:") {
+            self.start_fifo(path)
+        } else if playlist::is_playlist(filename) {
+            self.run_playlist(filename)
+        } else if archive::split_source(filename).is_some() {
+            self.submit(Job::for_source(filename))
+        } else if filename.starts_with("s3://") {
+            self.run_s3(filename)
+        } else if webdav::http_url(filename).is_some() {
+            self.run_webdav(filename)
+        } else if filename.starts_with("http://") ||
             filename.starts_with("https://") {
-
-                println!("GET {}", filename);
-                let uri = match filename.parse() {
-                    Ok(uri) => uri,
-                    Err(_) => return,
+                let info = PictureInfo {
+                    source: filename.to_owned(),
+                    .. PictureInfo::default()
                 };
-                let res = match get(&uri) {
+                self.run_url(filename, info)
+            } else {
+                let attr = match metadata(filename) {
+                    Ok(attr) => attr,
                     Err(e) => {
-                        println!("{}", e);
+                        println!("Cannot open {}: {}", filename, e);
                         return
-                    },
-                    Ok(res) => res
-                };
-                println!("HTTP {}: {:?}", res.status(), res.headers().get::<ContentType>());
-                let (is_jpeg, is_feed) = match res.headers().get::<ContentType>() {
-                    Some(&ContentType(ref mime)) if mime == &IMAGE_JPEG =>
-                        (true, false),
-                    Some(&ContentType(ref mime)) if mime == &TEXT_XML =>
-                        (false, true),
-                    Some(&ContentType(ref mime))
-                        if mime.type_() == APPLICATION &&
-                        mime.suffix()
-                        .map(|name| name.as_str()) == Some("xml") =>
-                        (false, true),
-                    Some(&ContentType(ref mime)) => {
-                        println!("Cannot handle content-type {}", mime);
-                        (false, false)
-                    },
-                    None =>
-                        (false, false),
+                    }
                 };
-                if is_jpeg {
-                    println!("Reading JPEG til end...");
-                    let body = res.body();
-                    self.load_jpeg(BufReader::new(body))
-                } else if is_feed {
-                    println!("Reading feed til end...");
-                    let body = res.body();
-                    self.run_feed(&uri, body)
-                }
-            } else {
-                let attr = metadata(filename).unwrap();
                 if attr.is_file() || attr.file_type().is_symlink() {
                     let lower_filename = filename.to_lowercase();
-                    if lower_filename.ends_with(".jpg") ||
+                    if let Some(kind) = ArchiveKind::from_filename(filename) {
+                        self.run_archive(filename, kind);
+                    } else if lower_filename.ends_with(".jpg") ||
                         lower_filename.ends_with(".jpeg") {
 
-                            let file = File::open(filename).unwrap();
-                            self.load_jpeg(BufReader::new(file));
+                            let info = PictureInfo {
+                                source: filename.to_owned(),
+                                .. PictureInfo::default()
+                            };
+                            self.submit(Job::new(Input::File(filename.to_owned()), info));
                         }
                 } else if attr.is_dir() {
                     let mut entries: Vec<(String, DirEntry)> = read_dir(filename)
@@ -97,14 +826,393 @@ impl<'a> Loader<'a> {
                             (entry.path().to_str().unwrap().to_lowercase(), entry)
                         })
                         .collect();
-                    entries.sort_by(|&(ref a, _), &(ref b, _)| a.cmp(b));
-                    for (_, entry) in entries {
+                    if self.shuffle {
+                        thread_rng().shuffle(&mut entries);
+                    } else {
+                        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+                    }
+                    for (lower_path, entry) in entries {
+                        /* Text files next to pictures are rarely playlists */
+                        if lower_path.ends_with(".txt") {
+                            continue
+                        }
                         self.run_filename(entry.path().to_str().unwrap());
                     }
                 }
             }
     }
 
+    /// Looks into a URL for feeds and HTML pages, pictures with an
+    /// extension are left to the workers
+    ///
+    /// info: for the picture, if it is one or a page leads to one
+    fn run_url(&self, url: &str, info: PictureInfo) {
+        if is_picture_url(url) {
+            self.submit(Job::new(Input::Url(url.to_owned()), info));
+            return
+        }
+        println!("GET {}", url);
+        let uri = match url.parse() {
+            Ok(uri) => uri,
+            Err(e) => {
+                println!("Invalid URL {}: {}", url, e);
+                return
+            }
+        };
+        let res = match self.wait(get_async(&uri, &self.request_options())) {
+            Err(e) => {
+                println!("{}", e);
+                return
+            },
+            Ok(res) => res
+        };
+        println!("HTTP {}", res.status());
+        let mime = res.headers().get::<ContentType>()
+            .map(|ContentType(mime)| mime.clone());
+        println!("Content-Type: {:?}", mime);
+        let start = match self.wait(res.read_start(SNIFF_LEN, self.limits.max_bytes)) {
+            Ok(start) => start,
+            Err(e) => {
+                println!("Error reading {}: {}", url, e);
+                return
+            }
+        };
+        let prefix = start.start();
+        let kind = sniff::content_kind(mime.as_ref(), &prefix[..prefix.len().min(SNIFF_LEN)]);
+        if kind == ContentKind::Unknown {
+            println!("Cannot handle content-type {:?}", mime);
+        }
+        let body = match kind {
+            ContentKind::Unknown =>
+                return,
+            _ =>
+                match self.wait(start.read_rest(self.limits.max_bytes, self.cancel.borrow().clone())) {
+                    Ok(body) => body,
+                    Err(e) => {
+                        println!("Error reading {}: {}", url, e);
+                        return
+                    }
+                },
+        };
+        match kind {
+            /* Already fetched, a worker only decodes it */
+            ContentKind::Jpeg | ContentKind::Image =>
+                self.submit(Job::new(Input::Data(body), info)),
+            ContentKind::Feed | ContentKind::JsonFeed => {
+                if self.playlists.borrow().iter().any(|playlist| playlist == url) {
+                    println!("Feed loop: {} includes itself", url);
+                    return
+                }
+                self.playlists.borrow_mut().push(url.to_owned());
+                if kind == ContentKind::Feed {
+                    self.run_feed(&uri, &body[..])
+                } else {
+                    self.run_json_feed(&uri, &body[..])
+                }
+                self.playlists.borrow_mut().pop();
+            },
+            ContentKind::Html => {
+                let html_depth = info.options.html_depth
+                    .or(self.entry.borrow().0.html_depth)
+                    .unwrap_or(self.html_depth);
+                if html_depth < 1 {
+                    println!("Not following HTML page {}", url);
+                    return
+                }
+                if let Some(image) = follow_html(url, &body[..]) {
+                    let info = PictureInfo {
+                        source: image.clone(),
+                        link: info.link.clone().or_else(|| Some(url.to_owned())),
+                        options: SourceOptions {
+                            html_depth: Some(html_depth - 1),
+                            .. info.options.clone()
+                        },
+                        .. info
+                    };
+                    self.submit(Job::new(Input::Url(image), info));
+                }
+            },
+            ContentKind::Unknown => (),
+        }
+    }
+
+    /// Loads lines from stdin as they arrive, and again in every loop
+    /// once it is closed
+    fn run_stdin(&self) {
+        let started = self.stdin.borrow().is_some();
+        let stdin = self.stdin.borrow_mut()
+            .get_or_insert_with(|| pipe::spawn_stdin(self.limits.max_bytes, self.pending.clone()))
+            .clone();
+        if !started {
+            /* The first time through the pending queue */
+            println!("Reading entries from stdin");
+            return
+        }
+        let (lines, picture, closed) = {
+            let stdin = stdin.lock().unwrap();
+            (stdin.lines.clone(), stdin.picture.is_some(), stdin.closed)
+        };
+        if picture {
+            self.run_stdin_picture();
+        } else if closed {
+            for line in lines {
+                self.run_filename(&line);
+            }
+        }
+    }
+
+    /// Raw data piped to stdin
+    fn run_stdin_picture(&self) {
+        let data = self.stdin.borrow().as_ref()
+            .and_then(|stdin| stdin.lock().unwrap().picture.clone());
+        if let Some(data) = data {
+            let info = PictureInfo {
+                source: pipe::PICTURE_SOURCE.to_owned(),
+                .. PictureInfo::default()
+            };
+            self.submit(Job::new(Input::Data(data), info));
+        }
+    }
+
+    /// Entries written to the pipe are loaded as soon as the current
+    /// picture is submitted, and only once
+    fn start_fifo(&self, path: &str) {
+        let mut fifos = self.fifos.borrow_mut();
+        if fifos.iter().all(|fifo| fifo != path) {
+            println!("Reading entries from {}", path);
+            fifos.push(path.to_owned());
+            pipe::spawn_fifo(path.to_owned(), self.pending.clone());
+        }
+    }
+
+    /// Loads entries from named pipes, outside any playlist entry
+    fn run_pending(&self) {
+        if self.draining.get() {
+            return
+        }
+        self.draining.set(true);
+        let outer = std::mem::take(&mut *self.entry.borrow_mut());
+        let outer_root = self.root.borrow().clone();
+        let outer_cancel = self.cancel.borrow().clone();
+        loop {
+            let next = self.pending.lock().unwrap().pop_front();
+            match next {
+                Some(filename) => {
+                    self.set_root(filename.clone(), Arc::new(AtomicBool::new(false)));
+                    self.run_filename(&filename)
+                },
+                None => break,
+            }
+        }
+        self.set_root(outer_root, outer_cancel);
+        *self.entry.borrow_mut() = outer;
+        self.draining.set(false);
+    }
+
+    /// Sleeps up to a second while nothing is piped
+    fn wait_pending(&self) {
+        for _ in 0..(1000 / PIPE_POLL_INTERVAL) {
+            if !self.pending.lock().unwrap().is_empty() {
+                self.run_pending();
+                return
+            }
+            thread::sleep(Duration::from_millis(PIPE_POLL_INTERVAL));
+        }
+    }
+
+    /// Lists a bucket below a prefix, in key order
+    fn run_s3(&self, url: &str) {
+        let (bucket, prefix) = match s3::parse_url(url) {
+            Some(parsed) => parsed,
+            None => {
+                println!("Invalid S3 URL {}, expected s3://bucket/prefix", url);
+                return
+            }
+        };
+        let keys = S3Client::from_env(self.request_options().tls)
+            .and_then(|client| client.list(bucket, prefix, self.limits.max_bytes, &self.cancel.borrow()));
+        self.after_cancel();
+        let mut keys = match keys {
+            Ok(keys) => keys,
+            Err(e) => {
+                println!("Error listing {}: {}", url, e);
+                return
+            }
+        };
+        if self.shuffle {
+            thread_rng().shuffle(&mut keys);
+        }
+        for key in keys {
+            if is_picture_url(&key) {
+                let source = format!("s3://{}/{}", bucket, key);
+                self.submit(Job::for_source(&source));
+            }
+        }
+    }
+
+    /// Walks a WebDAV collection like a directory
+    fn run_webdav(&self, url: &str) {
+        if is_picture_url(url) {
+            self.submit(Job::for_source(url));
+            return
+        }
+        self.walk_webdav(url, &mut HashSet::new(), 0);
+    }
+
+    /// Skips collections seen before in this walk, servers may link
+    /// back to a parent
+    fn walk_webdav(&self, url: &str, visited: &mut HashSet<String>, depth: usize) {
+        if !visited.insert(url.trim_end_matches('/').to_owned()) {
+            return
+        }
+        if depth > webdav::MAX_DEPTH {
+            println!("Not walking {}, more than {} collections deep", url, webdav::MAX_DEPTH);
+            return
+        }
+        let listing = webdav::list(url, &self.request_options(), self.limits.max_bytes, &self.cancel.borrow());
+        self.after_cancel();
+        let (mut files, mut collections) = match listing {
+            Ok(listing) => listing,
+            Err(e) => {
+                println!("Error listing {}: {}", url, e);
+                return
+            }
+        };
+        if self.shuffle {
+            thread_rng().shuffle(&mut files);
+        } else {
+            files.sort_by_key(|file| file.to_lowercase());
+        }
+        collections.sort_by_key(|collection| collection.to_lowercase());
+        for file in files {
+            if is_picture_url(&file) {
+                self.submit(Job::for_source(&file));
+            }
+        }
+        for collection in collections {
+            self.walk_webdav(&collection, visited, depth + 1);
+        }
+    }
+
+    /// Loads the pictures in an archive like those in a directory,
+    /// without extracting it
+    ///
+    /// Entries are listed first and read by the workers.
+    fn run_archive(&self, filename: &str, kind: ArchiveKind) {
+        let names = match kind {
+            ArchiveKind::Zip => archive::list_zip(filename),
+            _ => archive::list_tar(filename, kind),
+        };
+        let result = names.map(|mut names| {
+            if self.shuffle {
+                thread_rng().shuffle(&mut names);
+            } else {
+                names.sort_by_key(|name| name.to_lowercase());
+            }
+            for name in names {
+                let info = PictureInfo {
+                    source: format!("{}{}{}", filename, archive::SEPARATOR, name),
+                    .. PictureInfo::default()
+                };
+                let input = Input::ArchiveEntry {
+                    archive: filename.to_owned(),
+                    entry: name,
+                };
+                self.submit(Job::new(input, info));
+            }
+        });
+        if let Err(e) = result {
+            println!("Error reading archive {}: {}", filename, e);
+        }
+    }
+
+    /// Loads each entry with its directives, relative paths and URLs
+    /// are resolved against the playlist's location
+    fn run_playlist(&self, filename: &str) {
+        let is_url = filename.starts_with("http://") ||
+            filename.starts_with("https://");
+        /* Identify local playlists by their real path */
+        let key = if is_url {
+            filename.to_owned()
+        } else {
+            canonicalize(filename)
+                .ok()
+                .and_then(|path| path.to_str().map(|path| path.to_owned()))
+                .unwrap_or_else(|| filename.to_owned())
+        };
+        if self.playlists.borrow().contains(&key) {
+            println!("Playlist loop: {} includes itself", filename);
+            return
+        }
+
+        let text = if is_url {
+            println!("GET {}", filename);
+            let uri: Uri = match filename.parse() {
+                Ok(uri) => uri,
+                Err(_) => return,
+            };
+            let max_bytes = self.limits.max_bytes;
+            let cancel = self.cancel.borrow().clone();
+            self.wait(get_async(&uri, &self.request_options())
+                      .and_then(move |res| res.read_body(max_bytes, cancel)))
+                .map_err(|e| format!("{}", e))
+        } else {
+            File::open(filename)
+                .and_then(|file| read_limited(file, self.limits.max_bytes))
+                .map_err(|e| format!("{}", e))
+        };
+        let text = match text {
+            Ok(data) => String::from_utf8_lossy(&data).into_owned(),
+            Err(e) => {
+                println!("Error reading playlist {}: {}", filename, e);
+                return
+            }
+        };
+
+        self.playlists.borrow_mut().push(key);
+        for entry in playlist::parse(filename, &text) {
+            let location = if entry.location.starts_with("http://") ||
+                entry.location.starts_with("https://") ||
+                Path::new(&entry.location).is_absolute() {
+                    Some(entry.location.clone())
+                } else if is_url {
+                    filename.parse()
+                        .ok()
+                        .and_then(|base| uri_join(&base, &entry.location))
+                } else {
+                    Path::new(filename)
+                        .parent()
+                        .unwrap_or(Path::new("."))
+                        .join(&entry.location)
+                        .to_str()
+                        .map(|path| path.to_owned())
+                };
+            let location = match location {
+                Some(location) => location,
+                None => {
+                    println!("Cannot resolve {} in {}", entry.location, filename);
+                    continue
+                }
+            };
+
+            let mut options = entry.options;
+            options.request.bind_host(&location);
+            let inner = {
+                let outer = self.entry.borrow();
+                (options.or(&outer.0), entry.title.or_else(|| outer.1.clone()))
+            };
+            let outer = replace(&mut *self.entry.borrow_mut(), inner);
+            self.run_filename(&location);
+            *self.entry.borrow_mut() = outer;
+        }
+        self.playlists.borrow_mut().pop();
+    }
+
+    /// Request options of the current entry, then of the config
+    fn request_options(&self) -> RequestOptions {
+        self.entry.borrow().0.request.or(&self.request)
+    }
+
     fn run_feed<R: Read>(&self, base: &Uri, res: R) {
         println!("Reading feed and parsing...");
         match treexml::Document::parse(res) {
@@ -129,16 +1237,69 @@ impl<'a> Loader<'a> {
         }
     }
 
+    /// https://jsonfeed.org/version/1
+    fn run_json_feed<R: Read>(&self, base: &Uri, res: R) {
+        println!("Reading JSON feed and parsing...");
+        let feed: serde_json::Value = match serde_json::from_reader(res) {
+            Ok(feed) => feed,
+            Err(e) => {
+                println!("Error parsing JSON: {}", e);
+                return
+            }
+        };
+        let items = match feed.get("items").and_then(|items| items.as_array()) {
+            Some(items) => items,
+            None => {
+                println!("Error parsing JSON feed: no items!");
+                return
+            }
+        };
+        let string = |value: &serde_json::Value, key: &str| value.get(key)
+            .and_then(|value| value.as_str())
+            .map(|value| value.to_owned());
+        for item in items {
+            /* "image", or the first image attachment */
+            let image = string(item, "image")
+                .or_else(|| string(item, "banner_image"))
+                .or_else(|| item.get("attachments")
+                         .and_then(|attachments| attachments.as_array())
+                         .and_then(|attachments| attachments.iter()
+                                   .find(|attachment| string(attachment, "mime_type")
+                                         .map(|mime| mime.starts_with("image/"))
+                                         .unwrap_or(false)))
+                         .and_then(|attachment| string(attachment, "url")));
+            if let Some(url) = image.and_then(|href| uri_join(base, &href)) {
+                let info = PictureInfo {
+                    source: url.clone(),
+                    title: string(item, "title"),
+                    link: string(item, "url"),
+                    author: item.get("author")
+                        .and_then(|author| string(author, "name")),
+                    .. PictureInfo::default()
+                };
+                self.run_url(&url, info);
+            }
+        }
+    }
+
     fn load_feed_item(&self, base: &Uri, item: &treexml::Element) {
-        let run_link = |href| {
-            uri_join(base, href)
-                .map(|url| self.run_filename(&url));
+        let load_link = |href| {
+            if let Some(url) = uri_join(base, href) {
+                let info = PictureInfo {
+                    source: url.clone(),
+                    title: child_text(item, "title"),
+                    link: feed_item_link(item),
+                    author: feed_item_author(item),
+                    .. PictureInfo::default()
+                };
+                self.run_url(&url, info)
+            }
         };
         /* <atom:link rel="enclosure" href="http://..."/> */
         for content in item.filter_children(|el| el.name == "link") {
             match (content.attributes.get("rel"), content.attributes.get("href")) {
                 (Some(rel), Some(url)) if rel == "enclosure" => {
-                    run_link(url);
+                    load_link(url);
                     return
                 },
                 _ => ()
@@ -146,39 +1307,178 @@ impl<'a> Loader<'a> {
         }
         /* <media:content url="http://..."/> */
         for content in item.filter_children(|el| el.name == "content") {
-            match content.attributes.get("url") {
-                Some(url) => {
-                    run_link(url);
-                    return
-                },
-                None => ()
+            if let Some(url) = content.attributes.get("url") {
+                load_link(url);
+                return
             }
         }
     }
+}
 
-    pub fn load_jpeg<R: Read>(&self, file: R) -> () {
-        let t1 = get_us();
-        println!("Load JPEG...");
-        let image = match decoder_to_image(jpeg::JPEGDecoder::new(file)) {
-            Ok(image) => { println!("Loaded image!"); image.to_rgba() },
-            Err(e) => {
-                println!("Error loading JPEG: {}", e);
-                return
-            }
-        };
-        let t2 = get_us();
-        let image_dimensions = image.dimensions();
-        let t3 = get_us();
-        let image = RawImage2d::from_raw_rgba_reversed(&image.into_raw(), image_dimensions);
-        let t4 = get_us();
-        println!("Loaded image in {} + {} + {} us", t2 - t1, t3 - t2, t4 - t3);
-        match self.tx.send(image) {
-            Ok(()) => (),
-            Err(e) => {
-                println!("Error transferring JPEG to main thread: {}", e);
-                return
-            }
+fn child_text(el: &treexml::Element, name: &str) -> Option<String> {
+    el.find_child(|child| child.name == name)
+        .and_then(|child| child.text.clone().or(child.cdata.clone()))
+        .map(|text| text.trim().to_owned())
+        .filter(|text| !text.is_empty())
+}
+
+/// RSS <link>, ATOM <link rel="alternate">
+fn feed_item_link(item: &treexml::Element) -> Option<String> {
+    item.filter_children(|child| child.name == "link")
+        .filter_map(|link| match (link.attributes.get("rel"), link.attributes.get("href")) {
+            (None, Some(href)) => Some(href.clone()),
+            (Some(rel), Some(href)) if rel == "alternate" => Some(href.clone()),
+            _ => None,
+        })
+        .next()
+        .or_else(|| child_text(item, "link"))
+}
+
+/// RSS <author> or <dc:creator>, ATOM <author><name>
+fn feed_item_author(item: &treexml::Element) -> Option<String> {
+    item.find_child(|child| child.name == "author")
+        .and_then(|author| child_text(author, "name"))
+        .or_else(|| child_text(item, "author"))
+        .or_else(|| child_text(item, "creator"))
+}
+
+/// Decodes JPEG with load_jpeg(), anything else with the image crate
+///
+/// Returns the image along with its original size
+pub fn load_image(data: Vec<u8>, display_size: (u32, u32), max_pixels: u64) -> Option<(RawImage2d<'static, u8>, (u32, u32))> {
+    let t1 = get_us();
+    let reader = Cursor::new(&data[..]);
+    let decoded = match guess_format(&data) {
+        Ok(ImageFormat::PNG) =>
+            decode_checked(png::PNGDecoder::new(reader), max_pixels),
+        Ok(ImageFormat::GIF) =>
+            decode_checked(gif::Decoder::new(reader), max_pixels),
+        Ok(ImageFormat::TIFF) =>
+            tiff::TIFFDecoder::new(reader)
+            .and_then(|decoder| decode_checked(decoder, max_pixels)),
+        Ok(ImageFormat::WEBP) =>
+            decode_checked(webp::WebpDecoder::new(reader), max_pixels),
+        _ =>
+            return load_jpeg(reader, display_size, max_pixels),
+    };
+    let image = match decoded {
+        Ok(image) => image,
+        Err(e) => {
+            println!("Error loading image: {}", e);
+            return None
+        }
+    };
+    let original_size = image.dimensions();
+    let image = scale_image(image, display_size).to_rgba();
+    let image_dimensions = image.dimensions();
+    let image = RawImage2d::from_raw_rgba_reversed(&image.into_raw(), image_dimensions);
+    println!("Loaded {}x{} image in {} us", image_dimensions.0, image_dimensions.1, get_us() - t1);
+    Some((image, original_size))
+}
+
+/// Returns the image along with its original size
+pub fn load_jpeg<R: Read>(file: R, display_size: (u32, u32), max_pixels: u64) -> Option<(RawImage2d<'static, u8>, (u32, u32))> {
+    let t1 = get_us();
+    println!("Load JPEG...");
+    let (image, original_size) = match decode_jpeg_scaled(file, display_size, max_pixels) {
+        Ok(decoded) => { println!("Loaded image!"); decoded },
+        Err(e) => {
+            println!("Error loading JPEG: {}", e);
+            return None
         }
+    };
+    let t2 = get_us();
+    let image = scale_image(image, display_size).to_rgba();
+    let image_dimensions = image.dimensions();
+    let t3 = get_us();
+    let image = RawImage2d::from_raw_rgba_reversed(&image.into_raw(), image_dimensions);
+    let t4 = get_us();
+    println!("Loaded {}x{} image in {} + {} + {} us", image_dimensions.0, image_dimensions.1, t2 - t1, t3 - t2, t4 - t3);
+    Some((image, original_size))
+}
+
+/// Size that still covers the display at maximum zoom
+///
+/// Never scales up. Returns the original size while the display size
+/// is unknown.
+fn scaled_dimensions(width: u32, height: u32, display_size: (u32, u32)) -> (u32, u32) {
+    let (display_width, display_height) = display_size;
+    if display_width == 0 || display_height == 0 {
+        return (width, height)
+    }
+    let scale = MAX_ZOOM * (display_width as f32 / width as f32)
+        .max(display_height as f32 / height as f32);
+    if scale >= 1.0 {
+        (width, height)
+    } else {
+        ((width as f32 * scale).ceil() as u32,
+         (height as f32 * scale).ceil() as u32)
+    }
+}
+
+/// Decodes a JPEG, letting the decoder skip DCT coefficients when the
+/// image is much larger than the display
+///
+/// Also returns the size before scaling.
+fn decode_jpeg_scaled<R: Read>(file: R, display_size: (u32, u32), max_pixels: u64) -> ImageResult<(DynamicImage, (u32, u32))> {
+    let format_error = |e: jpeg_decoder::Error| ImageError::FormatError(format!("{}", e));
+    let mut decoder = jpeg_decoder::Decoder::new(file);
+    decoder.read_info().map_err(&format_error)?;
+    let info = decoder.info().unwrap();
+    /* Progressive JPEGs keep all coefficients, however much is scaled */
+    check_pixels(info.width as u32, info.height as u32, max_pixels)?;
+    let (width, height) = scaled_dimensions(info.width as u32, info.height as u32, display_size);
+    let (w, h) = decoder.scale(width as u16, height as u16).map_err(&format_error)?;
+    let (w, h) = (w as u32, h as u32);
+    let buf = decoder.decode().map_err(&format_error)?;
+
+    let image = match info.pixel_format {
+        jpeg_decoder::PixelFormat::RGB24 =>
+            ImageBuffer::from_raw(w, h, buf).map(DynamicImage::ImageRgb8),
+        jpeg_decoder::PixelFormat::L8 =>
+            ImageBuffer::from_raw(w, h, buf).map(DynamicImage::ImageLuma8),
+        jpeg_decoder::PixelFormat::CMYK32 => {
+            let buf = buf.chunks(4)
+                .flat_map(|cmyk| {
+                    let k = 255 - cmyk[3] as u32;
+                    (0..3).map(move |i| ((255 - cmyk[i] as u32) * k / 255) as u8)
+                })
+                .collect();
+            ImageBuffer::from_raw(w, h, buf).map(DynamicImage::ImageRgb8)
+        },
+    };
+    match image {
+        Some(image) => Ok((image, (info.width as u32, info.height as u32))),
+        None => Err(ImageError::DimensionError)
+    }
+}
+
+/// Fails with DimensionError instead of letting a picture with
+/// absurd dimensions take it all
+fn check_pixels(width: u32, height: u32, max_pixels: u64) -> ImageResult<()> {
+    if width as u64 * height as u64 > max_pixels {
+        println!("{}x{} pixels exceed the memory budget of {} pixels", width, height, max_pixels);
+        Err(ImageError::DimensionError)
+    } else {
+        Ok(())
+    }
+}
+
+/// Reads the dimensions from the header before decoding
+fn decode_checked<D: ImageDecoder>(mut decoder: D, max_pixels: u64) -> ImageResult<DynamicImage> {
+    let (width, height) = decoder.dimensions()?;
+    check_pixels(width, height, max_pixels)?;
+    decoder_to_image(decoder)
+}
+
+/// Resamples what DCT scaling left too large
+fn scale_image(image: DynamicImage, display_size: (u32, u32)) -> DynamicImage {
+    let (width, height) = image.dimensions();
+    let (scaled_width, scaled_height) = scaled_dimensions(width, height, display_size);
+    if (scaled_width, scaled_height) == (width, height) {
+        image
+    } else {
+        image.resize_exact(scaled_width, scaled_height, FilterType::Lanczos3)
     }
 }
 
@@ -194,9 +1494,9 @@ pub fn decoder_to_image<I: ImageDecoder>(codec: I) -> ImageResult<DynamicImage>
 
     let mut codec = codec;
 
-    let color  = try!(codec.colortype());
-    let buf    = try!(codec.read_image());
-    let (w, h) = try!(codec.dimensions());
+    let color  = codec.colortype()?;
+    let buf    = codec.read_image()?;
+    let (w, h) = codec.dimensions()?;
 
     let image = match (color, buf) {
         (ColorType::RGB(8), U8(buf)) => {
@@ -244,10 +1544,14 @@ pub fn decoder_to_image<I: ImageDecoder>(codec: I) -> ImageResult<DynamicImage>
     }
 }
 
-/// Incomplete
+/// Resolves a link against the document it was found in, without
+/// handling `..`
 fn uri_join(base: &Uri, href: &str) -> Option<String> {
     if href.starts_with("http://") || href.starts_with("https://")  {
         Some(href.to_owned())
+    } else if href.starts_with("//") {
+        base.scheme()
+            .map(|scheme| format!("{}:{}", scheme, href))
     } else if href.starts_with("/") {
         base.scheme()
             .and_then(
@@ -258,13 +1562,18 @@ fn uri_join(base: &Uri, href: &str) -> Option<String> {
                     )
             )
     } else {
-        // Incomplete!
+        /* Relative to the base's directory */
+        let path = base.path();
+        let dir = match path.rfind('/') {
+            Some(i) => &path[..i + 1],
+            None => "/",
+        };
         base.scheme()
             .and_then(
                 |scheme| base.authority()
                     .map(
                         |authority|
-                        format!("{}://{}/{}", scheme, authority, href)
+                        format!("{}://{}{}{}", scheme, authority, dir, href)
                     )
             )
     }
@@ -0,0 +1,193 @@
+//! Local stand-ins for the servers that sources are loaded from, a
+//! loader to run against them and an offscreen GL context
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use glium::HeadlessRenderer;
+use glium::glutin::HeadlessRendererBuilder;
+use image::{ColorType, ImageBuffer, Rgb};
+use image::jpeg::JPEGEncoder;
+use native_tls::{Identity, TlsAcceptor};
+use tempfile::TempDir;
+
+use config::Config;
+use favorites::Favorites;
+use source::{Loader, LoaderHandle};
+
+/// What a handler gets to see of a request
+pub struct Request {
+    pub method: String,
+    /// With the query
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+type Handler = Arc<dyn Fn(&Request) -> (u16, Vec<u8>) + Send + Sync>;
+
+/// Answers HTTP/1.1 requests on a local port with whatever handler
+/// returns, until the test ends
+pub fn serve<H>(handler: H) -> SocketAddr
+    where H: Fn(&Request) -> (u16, Vec<u8>) + Send + Sync + 'static
+{
+    let handler: Handler = Arc::new(handler);
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move|| {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            let handler = handler.clone();
+            thread::spawn(move|| handle_connection(stream, handler));
+        }
+    });
+    addr
+}
+
+/// Like serve() but over TLS with the identity from a PKCS#12 file
+pub fn serve_tls<H>(identity: &Path, password: &str, handler: H) -> SocketAddr
+    where H: Fn(&Request) -> (u16, Vec<u8>) + Send + Sync + 'static
+{
+    let mut der = vec![];
+    File::open(identity).unwrap()
+        .read_to_end(&mut der).unwrap();
+    let identity = Identity::from_pkcs12(&der, password).unwrap();
+    let acceptor = TlsAcceptor::new(identity).unwrap();
+    let handler: Handler = Arc::new(handler);
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move|| {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            let acceptor = acceptor.clone();
+            let handler = handler.clone();
+            thread::spawn(move|| {
+                /* Refused handshakes are what some tests are after */
+                if let Ok(stream) = acceptor.accept(stream) {
+                    handle_connection(stream, handler);
+                }
+            });
+        }
+    });
+    addr
+}
+
+/// Keeps the connection alive for as long as the client wants
+fn handle_connection<S: Read + Write>(stream: S, handler: Handler) {
+    let mut stream = BufReader::new(stream);
+    while let Some(req) = read_request(&mut stream) {
+        let (status, body) = handler(&req);
+        let head = format!("HTTP/1.1 {} Test\r\nContent-Length: {}\r\n\r\n", status, body.len());
+        let stream = stream.get_mut();
+        if stream.write_all(head.as_bytes()).is_err() ||
+            stream.write_all(&body).is_err() ||
+            stream.flush().is_err() {
+                return
+            }
+    }
+}
+
+fn read_request<R: BufRead>(stream: &mut R) -> Option<Request> {
+    let mut line = String::new();
+    if stream.read_line(&mut line).ok()? == 0 {
+        return None
+    }
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?.to_owned();
+    let path = parts.next()?.to_owned();
+    let mut headers = vec![];
+    loop {
+        let mut line = String::new();
+        stream.read_line(&mut line).ok()?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break
+        }
+        let (name, value) = line.split_once(':')?;
+        headers.push((name.trim().to_owned(), value.trim().to_owned()));
+    }
+    let mut req = Request { method, path, headers, body: vec![] };
+    if let Some(len) = req.header("Content-Length").and_then(|len| len.parse().ok()) {
+        req.body = vec![0; len];
+        stream.read_exact(&mut req.body).ok()?;
+    } else if req.header("Transfer-Encoding").map(|te| te.eq_ignore_ascii_case("chunked")).unwrap_or(false) {
+        loop {
+            let mut size = String::new();
+            stream.read_line(&mut size).ok()?;
+            let size = usize::from_str_radix(size.trim(), 16).ok()?;
+            let mut chunk = vec![0; size + 2];
+            stream.read_exact(&mut chunk).ok()?;
+            if size == 0 {
+                break
+            }
+            req.body.extend_from_slice(&chunk[..size]);
+        }
+    }
+    Some(req)
+}
+
+/// A small picture that decodes
+pub fn jpeg() -> Vec<u8> {
+    let image = ImageBuffer::from_pixel(8, 8, Rgb([200u8, 100, 50]));
+    let mut data = vec![];
+    JPEGEncoder::new(&mut data)
+        .encode(&image.into_raw(), 8, 8, ColorType::RGB(8))
+        .unwrap();
+    data
+}
+
+/// A loader over sources for a small screen, with its favorites in a
+/// directory that lives as long as the returned one
+pub fn loader(sources: &[&str]) -> (LoaderHandle, Arc<Mutex<Favorites>>, TempDir) {
+    let dir = ::tempfile::tempdir().unwrap();
+    let config = Config::from_args(vec!["--workers=1".to_owned()].into_iter()).unwrap();
+    let favorites = Arc::new(Mutex::new(Favorites::open(&dir.path().join("favorites.json"), 0)));
+    let sources = sources.iter().map(|source| source.to_string()).collect();
+    let handle = Loader::spawn(&config, sources, None, None, favorites.clone(), None);
+    *handle.display_size.lock().unwrap() = (64, 64);
+    (handle, favorites, dir)
+}
+
+/// Runs a loader over sources and returns what it delivers, in order,
+/// giving up after a while
+pub fn load(sources: &[&str], pictures: usize) -> Vec<String> {
+    let (handle, _, _dir) = loader(sources);
+    let source_rx = handle.source_rx.lock().unwrap();
+    (0..pictures)
+        .map_while(|_| source_rx.recv_timeout(Duration::from_secs(10)).ok())
+        .map(|loaded| loaded.info.source)
+        .collect()
+}
+
+/// An offscreen context, panicking when the system cannot create one
+///
+/// Tests using it are `#[ignore]`d, `cargo test -- --ignored` runs them
+/// where OSMesa or a similar headless driver is installed.
+pub fn headless(width: u32, height: u32) -> HeadlessRenderer {
+    let context = HeadlessRendererBuilder::new(width, height).build()
+        .map_err(|e| e.to_string())
+        .and_then(|context| HeadlessRenderer::new(context).map_err(|e| e.to_string()));
+    match context {
+        Ok(context) => context,
+        Err(e) => panic!("No headless OpenGL: {}", e),
+    }
+}
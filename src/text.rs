@@ -0,0 +1,278 @@
+use std::borrow::Cow;
+use glium::{Surface, Program, DrawParameters, Blend, Frame, Rect};
+use glium::backend::Facade;
+use glium::texture::{Texture2d, RawImage2d, ClientFormat, UncompressedFloatFormat, MipmapsOption};
+use glium::uniforms::{MinifySamplerFilter, MagnifySamplerFilter};
+use glium::vertex::VertexBuffer;
+use glium::index::{NoIndices, PrimitiveType};
+use rusttype::{Font, FontCollection, Scale, PositionedGlyph, point};
+use rusttype::gpu_cache::{Cache, CacheWriteErr};
+
+use config::Anchor;
+
+const FONT_DATA: &[u8] = include_bytes!("../assets/DejaVuSans.ttf");
+const CACHE_SIZE: u32 = 1024;
+/// The glyph cache doubles up to this size when a block of text doesn't fit
+const MAX_CACHE_SIZE: u32 = 4096;
+
+#[derive(Copy, Clone)]
+struct TextVertex {
+    position: [f32; 2],
+    tex_coords: [f32; 2],
+    colour: [f32; 4],
+    /// 0.0 for the background box
+    textured: f32,
+}
+/* The macro still uses mem::uninitialized */
+#[allow(deprecated)]
+const _: () = {
+    implement_vertex!(TextVertex, position, tex_coords, colour, textured);
+};
+
+/// How a block of text is placed within a viewport
+#[derive(Clone, Debug)]
+pub struct TextStyle {
+    pub anchor: Anchor,
+    /// Distance to the viewport edges, in pixels
+    pub margin: f32,
+    /// Line height, in pixels
+    pub size: f32,
+    pub colour: [f32; 4],
+    /// Draw a translucent box behind the text
+    pub background: bool,
+}
+
+/// Draws lines of text with glyphs from a texture atlas
+pub struct TextRenderer {
+    font: Font<'static>,
+    cache: Cache<'static>,
+    cache_texture: Texture2d,
+    program: Program,
+}
+
+impl TextRenderer {
+    pub fn new<F: Facade>(display: &F) -> Self {
+        let font = FontCollection::from_bytes(FONT_DATA)
+            .and_then(FontCollection::into_font)
+            .unwrap();
+        let (cache, cache_texture) = new_cache(display, CACHE_SIZE);
+
+        let vertex_shader_src = r#"
+            #version 140
+
+            in vec2 position;
+            in vec2 tex_coords;
+            in vec4 colour;
+            in float textured;
+
+            out vec2 v_tex_coords;
+            out vec4 v_colour;
+            out float v_textured;
+
+            void main() {
+                gl_Position = vec4(position, 0.0, 1.0);
+                v_tex_coords = tex_coords;
+                v_colour = colour;
+                v_textured = textured;
+            }
+        "#;
+
+        let fragment_shader_src = r#"
+            #version 140
+
+            uniform sampler2D tex;
+            uniform float alpha;
+
+            in vec2 v_tex_coords;
+            in vec4 v_colour;
+            in float v_textured;
+
+            out vec4 frag_color;
+
+            void main() {
+                float coverage = mix(1.0, texture(tex, v_tex_coords).r, v_textured);
+                frag_color = vec4(v_colour.rgb, v_colour.a * coverage * alpha);
+            }
+        "#;
+
+        let program = Program::from_source(display, vertex_shader_src, fragment_shader_src,
+                                           None).unwrap();
+        TextRenderer {
+            font,
+            cache,
+            cache_texture,
+            program,
+        }
+    }
+
+    pub fn draw<F: Facade>(&mut self, display: &F, target: &mut Frame, viewport: Rect, lines: &[String], style: &TextStyle, alpha: f32) {
+        if lines.is_empty() || alpha <= 0.0 {
+            return
+        }
+
+        let (glyphs, block_width, block_height) = self.layout(lines, style);
+
+        /* Top left corner of the block within the viewport */
+        let padding = padding(style);
+        let (x_align, y_align) = style.anchor.alignment();
+        let outer_width = block_width + 2.0 * padding;
+        let outer_height = block_height + 2.0 * padding;
+        let left = style.margin +
+            x_align * (viewport.width as f32 - outer_width - 2.0 * style.margin);
+        let top = style.margin +
+            y_align * (viewport.height as f32 - outer_height - 2.0 * style.margin);
+
+        if !self.cache_glyphs(display, &glyphs) {
+            return
+        }
+
+        /* Pixels to normalized device coordinates */
+        let to_ndc = |x: f32, y: f32| [
+            2.0 * x / viewport.width as f32 - 1.0,
+            1.0 - 2.0 * y / viewport.height as f32
+        ];
+        let mut vertices = vec![];
+        if style.background {
+            let colour = [0.0, 0.0, 0.0, 0.5];
+            push_quad(&mut vertices,
+                      to_ndc(left, top), to_ndc(left + outer_width, top + outer_height),
+                      [0.0, 0.0], [0.0, 0.0], colour, 0.0);
+        }
+        for glyph in &glyphs {
+            if let Ok(Some((uv_rect, screen_rect))) = self.cache.rect_for(0, glyph) {
+                let x = left + padding;
+                let y = top + padding;
+                push_quad(&mut vertices,
+                          to_ndc(x + screen_rect.min.x as f32, y + screen_rect.min.y as f32),
+                          to_ndc(x + screen_rect.max.x as f32, y + screen_rect.max.y as f32),
+                          [uv_rect.min.x, uv_rect.min.y], [uv_rect.max.x, uv_rect.max.y],
+                          style.colour, 1.0);
+            }
+        }
+
+        if vertices.is_empty() {
+            return
+        }
+        let shape = VertexBuffer::new(display, &vertices).unwrap();
+        let tex = self.cache_texture.sampled()
+            .minify_filter(MinifySamplerFilter::Nearest)
+            .magnify_filter(MagnifySamplerFilter::Nearest);
+        let params = DrawParameters {
+            blend: Blend::alpha_blending(),
+            viewport: Some(viewport),
+            .. Default::default()
+        };
+        target.draw(
+            &shape,
+            NoIndices(PrimitiveType::TrianglesList),
+            &self.program,
+            &uniform! { tex: tex, alpha: alpha },
+            &params
+        ).unwrap();
+    }
+
+    /// Height of the block drawn for the lines, including the
+    /// background box but not the margin
+    pub fn measure(&self, lines: &[String], style: &TextStyle) -> f32 {
+        if lines.is_empty() {
+            return 0.0
+        }
+        self.layout(lines, style).2 + 2.0 * padding(style)
+    }
+
+    /// Glyphs in pixels with the origin at the top left of the text
+    /// block, and the size of the block
+    fn layout(&self, lines: &[String], style: &TextStyle) -> (Vec<PositionedGlyph<'static>>, f32, f32) {
+        let scale = Scale::uniform(style.size);
+        let v_metrics = self.font.v_metrics(scale);
+        let line_height = v_metrics.ascent - v_metrics.descent + v_metrics.line_gap;
+        let mut glyphs: Vec<PositionedGlyph<'static>> = vec![];
+        let mut block_width = 0.0f32;
+        for (i, line) in lines.iter().enumerate() {
+            let baseline = point(0.0, i as f32 * line_height + v_metrics.ascent);
+            for glyph in self.font.layout(line, scale, baseline) {
+                if let Some(bb) = glyph.pixel_bounding_box() {
+                    block_width = block_width.max(bb.max.x as f32);
+                }
+                glyphs.push(glyph.standalone());
+            }
+        }
+        let block_height = lines.len() as f32 * line_height;
+        (glyphs, block_width, block_height)
+    }
+
+    /// Uploads the glyphs to the cache texture, growing it when they don't fit.
+    /// Returns false if the text can't be drawn.
+    fn cache_glyphs<F: Facade>(&mut self, display: &F, glyphs: &[PositionedGlyph<'static>]) -> bool {
+        loop {
+            for glyph in glyphs {
+                self.cache.queue_glyph(0, glyph.clone());
+            }
+            let result = {
+                let cache_texture = &self.cache_texture;
+                self.cache.cache_queued(|rect, data| {
+                    cache_texture.main_level().write(Rect {
+                        left: rect.min.x,
+                        bottom: rect.min.y,
+                        width: rect.width(),
+                        height: rect.height(),
+                    }, RawImage2d {
+                        data: Cow::Borrowed(data),
+                        width: rect.width(),
+                        height: rect.height(),
+                        format: ClientFormat::U8,
+                    });
+                })
+            };
+            let size = self.cache.dimensions().0;
+            match result {
+                Ok(()) => return true,
+                Err(CacheWriteErr::NoRoomForWholeQueue) if size < MAX_CACHE_SIZE => {
+                    let (cache, cache_texture) = new_cache(display, size * 2);
+                    self.cache = cache;
+                    self.cache_texture = cache_texture;
+                }
+                Err(e) => {
+                    println!("Cannot draw text: {:?}", e);
+                    self.cache.clear_queue();
+                    return false
+                }
+            }
+        }
+    }
+}
+
+/// Space between the text and the edges of its background box
+fn padding(style: &TextStyle) -> f32 {
+    if style.background { style.size / 4.0 } else { 0.0 }
+}
+
+/// An empty glyph cache and its texture
+fn new_cache<F: Facade>(display: &F, size: u32) -> (Cache<'static>, Texture2d) {
+    let cache = Cache::new(size, size, 0.1, 0.1);
+    let cache_texture = Texture2d::with_format(
+        display,
+        RawImage2d {
+            data: Cow::Owned(vec![0u8; (size * size) as usize]),
+            width: size,
+            height: size,
+            format: ClientFormat::U8,
+        },
+        UncompressedFloatFormat::U8,
+        MipmapsOption::NoMipmap
+    ).unwrap();
+    (cache, cache_texture)
+}
+
+/// Two triangles from the top left to the bottom right corner
+fn push_quad(vertices: &mut Vec<TextVertex>, min: [f32; 2], max: [f32; 2], uv_min: [f32; 2], uv_max: [f32; 2], colour: [f32; 4], textured: f32) {
+    let vertex = |position: [f32; 2], tex_coords: [f32; 2]| TextVertex {
+        position, tex_coords, colour, textured
+    };
+    vertices.push(vertex([min[0], min[1]], [uv_min[0], uv_min[1]]));
+    vertices.push(vertex([max[0], min[1]], [uv_max[0], uv_min[1]]));
+    vertices.push(vertex([min[0], max[1]], [uv_min[0], uv_max[1]]));
+    vertices.push(vertex([max[0], min[1]], [uv_max[0], uv_min[1]]));
+    vertices.push(vertex([max[0], max[1]], [uv_max[0], uv_max[1]]));
+    vertices.push(vertex([min[0], max[1]], [uv_min[0], uv_max[1]]));
+}
@@ -0,0 +1,218 @@
+use glium::backend::Facade;
+use glium::texture::{SrgbTexture2d, RawImage2d, MipmapsOption};
+use glium::vertex::VertexBuffer;
+
+use util::*;
+
+/// Texture size including the border, the GL_MAX_TEXTURE_SIZE that
+/// every OpenGL 3 implementation supports
+pub const TILE_SIZE: u32 = 1024;
+
+/// Neighbouring pixels copied around each tile
+const BORDER: u32 = 8;
+
+/// Mipmap levels beyond the base one. A texel of the last level spans
+/// `BORDER` pixels, so it never samples past the border of its tile.
+const MIPMAP_LEVELS: u32 = 3;
+
+#[derive(Copy, Clone)]
+pub struct Vertex {
+    pub position: [f32; 3],
+    pub tex_coords: [f32; 2],
+}
+/* The macro still uses mem::uninitialized */
+#[allow(deprecated)]
+const _: () = {
+    implement_vertex!(Vertex, position, tex_coords);
+};
+
+/// Part of a picture with its own texture and quad
+pub struct Tile {
+    pub texture: SrgbTexture2d,
+    pub shape: VertexBuffer<Vertex>,
+}
+
+/// Pixel rectangle of a tile, rows counted from the bottom
+#[derive(Clone, Copy, Debug)]
+struct TileRect {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+/// Splits an image into tiles that get uploaded one by one across
+/// frames
+pub struct TiledUpload {
+    data: Vec<u8>,
+    width: u32,
+    height: u32,
+    remaining: Vec<TileRect>,
+    tiles: Vec<Tile>,
+    mipmaps: bool,
+}
+
+impl TiledUpload {
+    pub fn new(image: RawImage2d<u8>, mipmaps: bool) -> Self {
+        let width = image.width;
+        let height = image.height;
+        let mut remaining = tile_rects(width, height);
+        /* Uploaded by pop() */
+        remaining.reverse();
+
+        TiledUpload {
+            data: image.data.into_owned(),
+            width,
+            height,
+            remaining,
+            tiles: vec![],
+            mipmaps,
+        }
+    }
+
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.remaining.is_empty()
+    }
+
+    /// Uploads at least one tile, then more until `deadline`
+    pub fn step<F: Facade>(&mut self, facade: &F, deadline: u64) {
+        while let Some(rect) = self.remaining.pop() {
+            let tile = self.upload_tile(facade, rect);
+            self.tiles.push(tile);
+
+            if get_us() >= deadline {
+                break
+            }
+        }
+    }
+
+    pub fn into_tiles(self) -> Vec<Tile> {
+        self.tiles
+    }
+
+    fn upload_tile<F: Facade>(&self, facade: &F, rect: TileRect) -> Tile {
+        let TileRect { x: tex_x, y: tex_y, width: tex_width, height: tex_height } =
+            texture_rect(rect, self.width, self.height);
+
+        let mut data = Vec::with_capacity((4 * tex_width * tex_height) as usize);
+        for row in tex_y..(tex_y + tex_height) {
+            let offset = (4 * (row * self.width + tex_x)) as usize;
+            data.extend_from_slice(&self.data[offset..(offset + 4 * tex_width as usize)]);
+        }
+        let image = RawImage2d::from_raw_rgba(data, (tex_width, tex_height));
+        let mipmaps = if self.mipmaps {
+            /* Smaller levels would blend in pixels beyond the border.
+             * glium panics when asked for more levels than the size allows.
+             */
+            let levels = 31 - tex_width.max(tex_height).leading_zeros();
+            MipmapsOption::AutoGeneratedMipmapsMax(MIPMAP_LEVELS.min(levels))
+        } else {
+            MipmapsOption::NoMipmap
+        };
+        let texture = SrgbTexture2d::with_mipmaps(facade, image, mipmaps).unwrap();
+
+        /* Texture coordinates of the tile without its border */
+        let u0 = (rect.x - tex_x) as f32 / tex_width as f32;
+        let u1 = (rect.x + rect.width - tex_x) as f32 / tex_width as f32;
+        let v0 = (rect.y - tex_y) as f32 / tex_height as f32;
+        let v1 = (rect.y + rect.height - tex_y) as f32 / tex_height as f32;
+        /* The whole picture spans [-1, 1] */
+        let x0 = -1.0 + 2.0 * rect.x as f32 / self.width as f32;
+        let x1 = -1.0 + 2.0 * (rect.x + rect.width) as f32 / self.width as f32;
+        let y0 = -1.0 + 2.0 * rect.y as f32 / self.height as f32;
+        let y1 = -1.0 + 2.0 * (rect.y + rect.height) as f32 / self.height as f32;
+        let shape = VertexBuffer::new(facade, &[
+            Vertex { position: [x0, y1, 0.0], tex_coords: [u0, v1] },
+            Vertex { position: [x1, y1, 0.0], tex_coords: [u1, v1] },
+            Vertex { position: [x0, y0, 0.0], tex_coords: [u0, v0] },
+            Vertex { position: [x1, y0, 0.0], tex_coords: [u1, v0] },
+        ]).unwrap();
+
+        Tile { texture, shape }
+    }
+}
+
+/// Covers the picture, leaving room for the border on both sides
+fn tile_rects(width: u32, height: u32) -> Vec<TileRect> {
+    let interior = TILE_SIZE - 2 * BORDER;
+    let mut rects = vec![];
+    for y in (0..height).step_by(interior as usize) {
+        for x in (0..width).step_by(interior as usize) {
+            rects.push(TileRect {
+                x, y,
+                width: interior.min(width - x),
+                height: interior.min(height - y),
+            });
+        }
+    }
+    rects
+}
+
+/// The pixels of a tile's texture: the tile with a border of
+/// neighbouring pixels, so that linear filtering of the base level and
+/// the mipmaps doesn't show seams between tiles
+fn texture_rect(rect: TileRect, width: u32, height: u32) -> TileRect {
+    let x = rect.x.saturating_sub(BORDER);
+    let y = rect.y.saturating_sub(BORDER);
+    TileRect {
+        x, y,
+        width: (rect.x + rect.width + BORDER).min(width) - x,
+        height: (rect.y + rect.height + BORDER).min(height) - y,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glium::texture::RawImage2d;
+    use testing;
+    use super::*;
+
+    #[test]
+    fn tiles_cover_large_pictures() {
+        for &(width, height) in &[(2500, 1100), (1008, 1008), (1009, 1), (20000, 3)] {
+            let mut covered = vec![0u8; (width * height) as usize];
+            for rect in tile_rects(width, height) {
+                let tex = texture_rect(rect, width, height);
+                assert!(tex.width <= TILE_SIZE && tex.height <= TILE_SIZE, "{:?}", tex);
+                assert!(tex.x + tex.width <= width && tex.y + tex.height <= height, "{:?}", tex);
+                /* The border surrounds the tile wherever the picture goes on */
+                assert_eq!(rect.x - tex.x, BORDER.min(rect.x));
+                assert_eq!(rect.y - tex.y, BORDER.min(rect.y));
+                assert_eq!(tex.x + tex.width - rect.x - rect.width,
+                           BORDER.min(width - rect.x - rect.width));
+                assert_eq!(tex.y + tex.height - rect.y - rect.height,
+                           BORDER.min(height - rect.y - rect.height));
+                for y in rect.y..(rect.y + rect.height) {
+                    for x in rect.x..(rect.x + rect.width) {
+                        covered[(y * width + x) as usize] += 1;
+                    }
+                }
+            }
+            assert!(covered.iter().all(|&n| n == 1), "{}x{}", width, height);
+        }
+    }
+
+    #[test]
+    #[ignore = "needs an offscreen OpenGL context, run with --ignored"]
+    fn uploads_pictures_larger_than_a_texture() {
+        let display = testing::headless(64, 64);
+        /* Wider than GL_MAX_TEXTURE_SIZE anywhere */
+        let (width, height) = (40000, 3);
+        let image = RawImage2d::from_raw_rgba(vec![128; (4 * width * height) as usize], (width, height));
+        let mut upload = TiledUpload::new(image, true);
+        while !upload.is_done() {
+            upload.step(&display, 0);
+        }
+        let tiles = upload.into_tiles();
+        assert_eq!(tiles.len(), tile_rects(width, height).len());
+        for tile in &tiles {
+            assert!(tile.texture.get_width() <= TILE_SIZE);
+            assert!(tile.texture.get_height().unwrap() <= TILE_SIZE);
+            assert!(tile.texture.get_mipmap_levels() <= MIPMAP_LEVELS + 1);
+        }
+    }
+}
@@ -1,6 +1,52 @@
+use std::path::PathBuf;
+use dirs;
 use time::now_utc;
 
 pub fn get_us() -> u64 {
     let now = now_utc().to_timespec();
     now.sec as u64 * 1000000 + now.nsec as u64 / 1000
 }
+
+/// Microseconds that stand still while paused
+pub struct Clock {
+    /// Total time spent paused
+    offset: u64,
+    paused_at: Option<u64>,
+}
+
+impl Clock {
+    pub fn new() -> Self {
+        Clock {
+            offset: 0,
+            paused_at: None,
+        }
+    }
+
+    pub fn now(&self) -> u64 {
+        self.paused_at.unwrap_or_else(get_us) - self.offset
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused_at.is_some()
+    }
+
+    pub fn pause(&mut self) {
+        if self.paused_at.is_none() {
+            self.paused_at = Some(get_us());
+        }
+    }
+
+    pub fn resume(&mut self) {
+        if let Some(paused_at) = self.paused_at.take() {
+            self.offset += get_us() - paused_at;
+        }
+    }
+}
+
+/// `rust-kenburns` in the user's cache directory, `$XDG_CACHE_HOME`
+/// or `~/.cache` on Linux
+pub fn cache_dir() -> PathBuf {
+    dirs::cache_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("rust-kenburns")
+}
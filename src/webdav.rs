@@ -0,0 +1,186 @@
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use hyper::{Method, Request, Uri};
+use hyper::header::ContentType;
+use treexml;
+
+use http::{self, Response, RequestOptions};
+
+/// Collections walked below the one given as a source
+pub const MAX_DEPTH: usize = 16;
+
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<propfind xmlns="DAV:"><prop><resourcetype/></prop></propfind>"#;
+
+/// `webdav://` and `webdavs://` to `http://` and `https://`
+pub fn http_url(url: &str) -> Option<String> {
+    if let Some(rest) = url.strip_prefix("webdav://") {
+        Some(format!("http://{}", rest))
+    } else {
+        url.strip_prefix("webdavs://")
+            .map(|rest| format!("https://{}", rest))
+    }
+}
+
+pub fn get(url: &str, options: &RequestOptions) -> Result<Response, String> {
+    let http_url = http_url(url).ok_or_else(|| format!("Not a WebDAV URL: {}", url))?;
+    let uri: Uri = http_url.parse().map_err(|e| format!("Invalid URL {}: {}", url, e))?;
+    let mut req = Request::new(Method::Get, uri);
+    options.apply(&mut req);
+    http::request(req, &options.tls)
+        .map_err(|e| format!("{}", e))
+}
+
+/// Files and subcollections of a collection, as `webdav://` URLs
+///
+/// max_bytes: limit for the PROPFIND response
+/// cancel: gives up on the request once set
+pub fn list(url: &str, options: &RequestOptions, max_bytes: u64, cancel: &Arc<AtomicBool>) -> Result<(Vec<String>, Vec<String>), String> {
+    let http_url = http_url(url).ok_or_else(|| format!("Not a WebDAV URL: {}", url))?;
+    /* Collections are expected to end with a slash */
+    let http_url = if http_url.ends_with('/') {
+        http_url
+    } else {
+        format!("{}/", http_url)
+    };
+    let uri: Uri = http_url.parse().map_err(|e| format!("Invalid URL {}: {}", url, e))?;
+    let scheme = if url.starts_with("webdavs://") { "webdavs" } else { "webdav" };
+    let authority = uri.authority().unwrap_or("").to_owned();
+    let own_path = uri.path().to_owned();
+
+    let mut req = Request::new(Method::Extension("PROPFIND".to_owned()), uri);
+    options.apply(&mut req);
+    req.headers_mut().set_raw("Depth", "1");
+    req.headers_mut().set(ContentType::xml());
+    req.set_body(PROPFIND_BODY);
+    let res = http::wait(http::request_async(req, &options.tls), cancel)
+        .map_err(|e| format!("{}", e))?;
+    if !res.status().is_success() {
+        return Err(format!("PROPFIND {}: HTTP {}", url, res.status()))
+    }
+    let body = http::wait(res.read_body(max_bytes, cancel.clone()), cancel)
+        .map_err(|e| format!("Error reading PROPFIND response: {}", e))?;
+    let root = match treexml::Document::parse(&body[..]) {
+        Ok(treexml::Document { root: Some(root), .. }) => root,
+        Ok(_) => return Err("Empty PROPFIND response".to_owned()),
+        Err(e) => return Err(format!("Error parsing PROPFIND response: {}", e)),
+    };
+
+    let mut files = vec![];
+    let mut collections = vec![];
+    for response in root.filter_children(|el| el.name == "response") {
+        let href = match response.find_child(|el| el.name == "href").and_then(|el| el.text.clone()) {
+            Some(href) => href,
+            None => continue,
+        };
+        /* Servers answer with paths or full URLs */
+        let path = match href.find("://") {
+            Some(i) => href[i + 3..].find('/').map(|j| href[i + 3 + j..].to_owned()).unwrap_or_default(),
+            None => href,
+        };
+        if path.trim_end_matches('/') == own_path.trim_end_matches('/') {
+            continue
+        }
+        let is_collection = response.filter_children(|el| el.name == "propstat")
+            .filter_map(|propstat| propstat.find_child(|el| el.name == "prop"))
+            .filter_map(|prop| prop.find_child(|el| el.name == "resourcetype"))
+            .any(|resourcetype| resourcetype.find_child(|el| el.name == "collection").is_some());
+        let url = format!("{}://{}{}", scheme, authority, path);
+        if is_collection {
+            collections.push(url);
+        } else {
+            files.push(url);
+        }
+    }
+    Ok((files, collections))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use http::Auth;
+    use testing::{self, serve};
+
+    fn multistatus(responses: &[(&str, bool)]) -> Vec<u8> {
+        let mut xml = r#"<?xml version="1.0"?><d:multistatus xmlns:d="DAV:">"#.to_owned();
+        for &(href, collection) in responses {
+            let resourcetype = if collection { "<d:collection/>" } else { "" };
+            xml.push_str(&format!(
+                "<d:response><d:href>{}</d:href><d:propstat><d:prop><d:resourcetype>{}</d:resourcetype></d:prop></d:propstat></d:response>",
+                href, resourcetype));
+        }
+        xml.push_str("</d:multistatus>");
+        xml.into_bytes()
+    }
+
+    #[test]
+    fn lists_files_and_collections() {
+        let seen = Arc::new(Mutex::new(vec![]));
+        let seen_server = seen.clone();
+        let addr = serve(move |req| {
+            seen_server.lock().unwrap().push((req.method.clone(), req.header("Depth").map(|depth| depth.to_owned()),
+                                              req.header("Authorization").map(|auth| auth.to_owned())));
+            match req.path.as_str() {
+                "/dav/photos/" => (207, multistatus(&[
+                    ("/dav/photos/", true),
+                    ("/dav/photos/a.jpg", false),
+                    ("http://elsewhere.example/dav/photos/b.jpg", false),
+                    ("/dav/photos/2017/", true),
+                ])),
+                _ => (404, vec![]),
+            }
+        });
+        let options = RequestOptions {
+            auth: vec![(Some("127.0.0.1".to_owned()), Auth::basic("me:secret"))],
+            .. RequestOptions::default()
+        };
+        let cancel = Arc::new(AtomicBool::new(false));
+        let url = format!("webdav://{}/dav/photos", addr);
+        let (files, collections) = list(&url, &options, 1 << 20, &cancel).unwrap();
+        assert_eq!(files, vec![format!("webdav://{}/dav/photos/a.jpg", addr),
+                               format!("webdav://{}/dav/photos/b.jpg", addr)]);
+        assert_eq!(collections, vec![format!("webdav://{}/dav/photos/2017/", addr)]);
+        {
+            let seen = seen.lock().unwrap();
+            assert_eq!(seen[0].0, "PROPFIND");
+            assert_eq!(seen[0].1.as_deref(), Some("1"));
+            assert_eq!(seen[0].2.as_deref(), Some("Basic bWU6c2VjcmV0"));
+        }
+
+        let missing = format!("webdav://{}/dav/missing/", addr);
+        assert!(list(&missing, &options, 1 << 20, &cancel).is_err());
+        assert!(list(&url, &options, 100, &cancel).is_err());
+    }
+
+    #[test]
+    fn walks_collections_once_and_not_too_deep() {
+        let jpeg = testing::jpeg();
+        let deepest = Arc::new(Mutex::new(0));
+        let deepest_server = deepest.clone();
+        let addr = serve(move |req| {
+            let path = req.path.as_str();
+            if req.method == "GET" {
+                return (200, jpeg.clone())
+            }
+            match path {
+                "/dav/" => (207, multistatus(&[("/dav/", true), ("/dav/a.jpg", false), ("/dav/sub/", true)])),
+                /* Links back to its parent, and to an endless chain */
+                "/dav/sub/" => (207, multistatus(&[("/dav/sub/b.jpg", false), ("/dav/", true), ("/dav/sub/deeper/", true)])),
+                _ => {
+                    let depth = path.matches("deeper/").count();
+                    let mut deepest = deepest_server.lock().unwrap();
+                    *deepest = (*deepest).max(depth);
+                    let child = format!("{}deeper/", path);
+                    (207, multistatus(&[(path, true), (&child, true)]))
+                },
+            }
+        });
+        let url = format!("webdav://{}/dav/", addr);
+        let a = format!("webdav://{}/dav/a.jpg", addr);
+        let b = format!("webdav://{}/dav/sub/b.jpg", addr);
+        assert_eq!(testing::load(&[&url], 4), vec![a.clone(), b.clone(), a, b]);
+        /* The source is depth 0, sub is 1 */
+        assert_eq!(*deepest.lock().unwrap(), MAX_DEPTH - 1);
+    }
+}
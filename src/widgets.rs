@@ -0,0 +1,105 @@
+use std::fs::{metadata, File};
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use time;
+
+use config::Anchor;
+use text::TextStyle;
+
+/// Check text files for changes once per second
+const FILE_CHECK_INTERVAL: u64 = 1_000;
+
+#[derive(Clone, Debug)]
+pub enum WidgetContent {
+    /// strftime format
+    Clock(String),
+    /// strftime format
+    Date(String),
+    Text(String),
+    /// Kept up to date by watch_files()
+    File {
+        path: String,
+        text: Arc<Mutex<String>>,
+    },
+}
+/// Text shown on top of the slideshow
+#[derive(Clone, Debug)]
+pub struct Widget {
+    pub content: WidgetContent,
+    pub style: TextStyle,
+    pub opacity: f32,
+}
+
+impl Widget {
+    pub fn new(content: WidgetContent) -> Self {
+        Widget {
+            content,
+            style: TextStyle {
+                anchor: Anchor::TopRight,
+                margin: 16.0,
+                size: 32.0,
+                colour: [1.0, 1.0, 1.0, 1.0],
+                background: false,
+            },
+            opacity: 1.0,
+        }
+    }
+
+    pub fn get_lines(&self) -> Vec<String> {
+        let text = match self.content {
+            WidgetContent::Clock(ref format) | WidgetContent::Date(ref format) =>
+                time::strftime(format, &time::now())
+                .unwrap_or_else(|e| format!("{}", e)),
+            WidgetContent::Text(ref text) =>
+                text.clone(),
+            WidgetContent::File { ref text, .. } =>
+                text.lock().unwrap().clone(),
+        };
+        text.lines()
+            .map(|line| line.to_owned())
+            .collect()
+    }
+}
+
+/// Starts a thread for each file widget that reloads the file when
+/// its mtime changes, so the renderers never wait for the disk
+///
+/// Clones of the widgets share the contents.
+pub fn watch_files(widgets: &[Widget]) {
+    for widget in widgets {
+        if let WidgetContent::File { ref path, ref text } = widget.content {
+            let path = path.clone();
+            let text = text.clone();
+            thread::spawn(move|| watch_file(&path, &text));
+        }
+    }
+}
+
+fn watch_file(path: &str, text: &Mutex<String>) {
+    let mut modified: Option<SystemTime> = None;
+    /* Only print errors when they change */
+    let mut last_error = None;
+    loop {
+        let new_modified = metadata(path)
+            .and_then(|attr| attr.modified())
+            .ok();
+        if new_modified.is_none() || new_modified != modified {
+            modified = new_modified;
+            let mut new_text = String::new();
+            let error = match File::open(path).and_then(|mut file| file.read_to_string(&mut new_text)) {
+                Ok(_) => None,
+                Err(e) => Some(format!("{}", e)),
+            };
+            if let Some(ref error) = error {
+                if last_error.as_ref() != Some(error) {
+                    println!("Error reading {}: {}", path, error);
+                }
+            }
+            last_error = error;
+            *text.lock().unwrap() = new_text;
+        }
+        thread::sleep(Duration::from_millis(FILE_CHECK_INTERVAL));
+    }
+}
@@ -0,0 +1,83 @@
+use glium::Display;
+use glium::glutin::{WindowBuilder, ContextBuilder, EventsLoop, MonitorId, CursorState};
+
+/// How to open the slideshow window
+#[derive(Clone, Debug, Default)]
+pub struct WindowOptions {
+    /// Start fullscreen
+    pub fullscreen: bool,
+    /// Monitor index or part of its name, primary monitor if none
+    pub monitor: Option<String>,
+    /// No window decorations
+    pub borderless: bool,
+    pub size: Option<(u32, u32)>,
+    pub position: Option<(i32, i32)>,
+    pub hide_cursor: bool,
+}
+
+impl WindowOptions {
+    pub fn window_builder(&self, fullscreen: bool, events_loop: &EventsLoop) -> WindowBuilder {
+        let mut window = WindowBuilder::new()
+            .with_title("Rust<KenBurns>")
+            .with_decorations(!self.borderless);
+        if let Some((width, height)) = self.size {
+            window = window.with_dimensions(width, height);
+        }
+        if fullscreen {
+            let monitor = self.find_monitor(events_loop);
+            window = window.with_fullscreen(Some(monitor));
+        }
+        window
+    }
+
+    pub fn context_builder<'a>(&self, vsync: bool) -> ContextBuilder<'a> {
+        ContextBuilder::new()
+            .with_depth_buffer(24)
+            .with_vsync(vsync)
+    }
+
+    /// Settings that can only be applied once the window exists
+    pub fn apply(&self, display: &Display, fullscreen: bool) {
+        let window = display.gl_window();
+        if let (false, Some((x, y))) = (fullscreen, self.position) {
+            window.set_position(x, y);
+        }
+        let cursor_state = if self.hide_cursor {
+            CursorState::Hide
+        } else {
+            CursorState::Normal
+        };
+        if let Err(e) = window.set_cursor_state(cursor_state) {
+            println!("Cannot set cursor state: {}", e);
+        }
+    }
+
+    fn find_monitor(&self, events_loop: &EventsLoop) -> MonitorId {
+        let monitors: Vec<MonitorId> = events_loop.get_available_monitors().collect();
+        for (i, monitor) in monitors.iter().enumerate() {
+            println!("Monitor {}: {:?} {:?}", i, monitor.get_name(), monitor.get_dimensions());
+        }
+
+        let selected = self.monitor.as_ref().and_then(|selector| {
+            match selector.parse::<usize>() {
+                Ok(index) =>
+                    monitors.get(index).cloned(),
+                Err(_) =>
+                    monitors.iter()
+                    .find(|monitor| monitor.get_name()
+                          .map(|name| name.contains(selector.as_str()))
+                          .unwrap_or(false))
+                    .cloned(),
+            }
+        });
+        match selected {
+            Some(monitor) => monitor,
+            None => {
+                if let Some(ref selector) = self.monitor {
+                    println!("Monitor {} not found, using primary", selector);
+                }
+                events_loop.get_primary_monitor()
+            }
+        }
+    }
+}